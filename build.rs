@@ -2,7 +2,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure().build_server(true).compile(
         &[
             "proto/build/bazel/remote/execution/v2/remote_execution.proto",
+            "proto/build/bazel/remote/logstream/v1/remote_logstream.proto",
+            "proto/build/bazel/remote/asset/v1/remote_asset.proto",
+            "proto/gaudi/worker/v1/worker.proto",
             "proto/google/bytestream/bytestream.proto",
+            "proto/google/devtools/remoteworkers/v1test2/bots.proto",
+            "proto/google/rpc/error_details.proto",
         ],
         &["proto"],
     )?;
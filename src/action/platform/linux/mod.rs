@@ -17,17 +17,21 @@ struct clone_args {
     cgroup: u64,
 }
 
-pub(crate) fn clone3() -> std::io::Result<(pid_t, pid_t)> {
+/// Clone into fresh namespaces, keeping the host network namespace if `share_network` is set.
+pub(crate) fn clone3(share_network: bool) -> std::io::Result<(pid_t, pid_t)> {
     let mut pidfd = -1;
 
+    let mut flags = libc::CLONE_NEWUSER
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWNS
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWPID
+        | libc::CLONE_PIDFD;
+    if !share_network {
+        flags |= libc::CLONE_NEWNET;
+    }
     let mut args = clone_args {
-        flags: (libc::CLONE_NEWUSER
-            | libc::CLONE_NEWNET
-            | libc::CLONE_NEWUTS
-            | libc::CLONE_NEWNS
-            | libc::CLONE_NEWIPC
-            | libc::CLONE_NEWPID
-            | libc::CLONE_PIDFD) as u64,
+        flags: flags as u64,
         pidfd: &mut pidfd as *mut libc::pid_t as u64,
         child_tid: 0,
         parent_tid: 0,
@@ -52,6 +56,7 @@ pub(crate) fn clone3() -> std::io::Result<(pid_t, pid_t)> {
     }
 }
 
+#[allow(dead_code)]
 pub unsafe fn syscall_0(n: usize) -> i32 {
     let ret: i32;
     asm!(
@@ -64,6 +69,7 @@ pub unsafe fn syscall_0(n: usize) -> i32 {
     ret
 }
 
+#[allow(dead_code)]
 pub unsafe fn syscall_1(n: usize, arg1: usize) -> i32 {
     let ret: i32;
     asm!(
@@ -91,6 +97,7 @@ pub unsafe fn syscall_2(n: usize, arg1: usize, arg2: usize) -> i32 {
     ret
 }
 
+#[allow(dead_code)]
 pub unsafe fn syscall_3(n: usize, arg1: usize, arg2: usize, arg3: usize) -> i32 {
     let ret: i32;
     asm!(
@@ -122,6 +129,7 @@ pub unsafe fn syscall_4(n: usize, arg1: usize, arg2: usize, arg3: usize, arg4: u
     ret
 }
 
+#[allow(dead_code)]
 pub unsafe fn syscall_5(
     n: usize,
     arg1: usize,
@@ -146,6 +154,7 @@ pub unsafe fn syscall_5(
     ret
 }
 
+#[allow(dead_code)]
 pub unsafe fn syscall_6(
     n: usize,
     arg1: usize,
@@ -1,39 +1,82 @@
+pub use build::bazel::remote::asset::v1::fetch_server::*;
+pub use build::bazel::remote::asset::v1::push_server::*;
+pub use build::bazel::remote::asset::v1::*;
 pub use build::bazel::remote::execution::v2::action_cache_server::*;
 pub use build::bazel::remote::execution::v2::capabilities_server::*;
 pub use build::bazel::remote::execution::v2::content_addressable_storage_server::*;
 pub use build::bazel::remote::execution::v2::execution_server::*;
 pub use build::bazel::remote::execution::v2::*;
+pub use build::bazel::remote::logstream::v1::log_stream_service_server::*;
+pub use build::bazel::remote::logstream::v1::*;
 pub use build::bazel::semver::SemVer;
+pub use gaudi::worker::v1 as worker;
+pub use gaudi::worker::v1::worker_status_server::*;
+pub use gaudi::worker::v1::Assignment;
+pub use google::bytestream::byte_stream_client::ByteStreamClient;
 pub use google::bytestream::byte_stream_server::*;
 pub use google::bytestream::*;
+pub use google::devtools::remoteworkers::v1test2 as remoteworkers;
+pub use google::devtools::remoteworkers::v1test2::bots_server::*;
 pub use google::longrunning;
 pub use google::longrunning::operations_server::*;
 pub use google::longrunning::*;
-pub use google::rpc::Status;
+pub use google::rpc::{bad_request, precondition_failure};
+pub use google::rpc::{BadRequest, PreconditionFailure, Status};
 
+#[allow(dead_code, clippy::all)]
 mod google {
+    pub mod api {
+        tonic::include_proto!("google.api");
+    }
     pub mod longrunning {
         tonic::include_proto!("google.longrunning");
     }
     pub mod bytestream {
         tonic::include_proto!("google.bytestream");
     }
+    pub mod devtools {
+        pub mod remoteworkers {
+            pub mod v1test2 {
+                tonic::include_proto!("google.devtools.remoteworkers.v1test2");
+            }
+        }
+    }
     pub mod rpc {
         tonic::include_proto!("google.rpc");
     }
 }
 
+#[allow(dead_code, clippy::all)]
+mod gaudi {
+    pub mod worker {
+        pub mod v1 {
+            tonic::include_proto!("gaudi.worker.v1");
+        }
+    }
+}
+
+#[allow(dead_code, clippy::all)]
 pub mod build {
     pub mod bazel {
         pub mod semver {
             tonic::include_proto!("build.bazel.semver");
         }
         pub mod remote {
+            pub mod asset {
+                pub mod v1 {
+                    tonic::include_proto!("build.bazel.remote.asset.v1");
+                }
+            }
             pub mod execution {
                 pub mod v2 {
                     tonic::include_proto!("build.bazel.remote.execution.v2");
                 }
             }
+            pub mod logstream {
+                pub mod v1 {
+                    tonic::include_proto!("build.bazel.remote.logstream.v1");
+                }
+            }
         }
     }
 }
@@ -0,0 +1,420 @@
+//! Assets named by URI, as served by the Remote Asset API.
+//!
+//! Nothing is downloaded: remote URIs are looked up in a local mirror directory and `file://`
+//! URIs are read from the server's filesystem when allowed. Pushed assets are recorded in the
+//! [`AssetIndex`](crate::asset_index::AssetIndex) instead.
+
+use crate::api;
+use crate::config::FetchConfig;
+use crate::content_storage::{CasError, ContentStorage};
+use crate::sandboxed_action::resolve_in_root;
+use base64::Engine;
+use prost::Message;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use thiserror::Error;
+use tracing::{info, instrument};
+use url::Url;
+
+/// Qualifier carrying a Subresource Integrity checksum of the content.
+pub const CHECKSUM_SRI: &str = "checksum.sri";
+
+/// Qualifiers that describe how to download from origin, meaningless for local copies.
+const IGNORED_QUALIFIERS: &[&str] = &["bazel.canonical_id"];
+const IGNORED_QUALIFIER_PREFIXES: &[&str] = &["http_header:", "http_header_url:"];
+
+#[derive(Error, Debug)]
+pub enum AssetError {
+    #[error("I/O: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("CAS: {0}")]
+    Cas(#[from] CasError),
+
+    #[error("\"{0}\" not supported")]
+    UnsupportedQualifier(String),
+
+    #[error("Invalid {}: {0:?}", CHECKSUM_SRI)]
+    InvalidChecksum(String),
+
+    #[error("Invalid URI {0}: {1}")]
+    InvalidUri(String, String),
+
+    #[error("{0} is not available from this server")]
+    Disallowed(String),
+
+    #[error("{0} was not found")]
+    NotFound(String),
+
+    #[error("Content of {0} does not match its {}", CHECKSUM_SRI)]
+    ChecksumMismatch(String),
+
+    #[error("{0} is a directory, its {} cannot be checked", CHECKSUM_SRI)]
+    Unverifiable(String),
+
+    #[error("{0} is neither a directory nor a tar archive")]
+    NotADirectory(String),
+
+    #[error("Blob {0} is missing from the CAS")]
+    MissingContent(String),
+
+    #[error("Invalid {0}: {1}")]
+    InvalidTimestamp(String, String),
+}
+
+impl AssetError {
+    /// Whether the asset could not be fetched from this URI, reported in the response instead
+    /// of failing the call so the next URI can be tried.
+    pub fn is_fetch_error(&self) -> bool {
+        !matches!(
+            self,
+            AssetError::Io(_)
+                | AssetError::Cas(_)
+                | AssetError::UnsupportedQualifier(_)
+                | AssetError::InvalidChecksum(_)
+                | AssetError::MissingContent(_)
+                | AssetError::InvalidTimestamp(..)
+        )
+    }
+
+    pub fn code(&self) -> tonic::Code {
+        match self {
+            AssetError::Io(_) | AssetError::Cas(_) => tonic::Code::Internal,
+            AssetError::UnsupportedQualifier(_)
+            | AssetError::InvalidChecksum(_)
+            | AssetError::InvalidUri(..)
+            | AssetError::NotADirectory(_)
+            | AssetError::InvalidTimestamp(..) => tonic::Code::InvalidArgument,
+            AssetError::Disallowed(_) => tonic::Code::PermissionDenied,
+            AssetError::NotFound(_) => tonic::Code::NotFound,
+            AssetError::ChecksumMismatch(_) | AssetError::Unverifiable(_) => tonic::Code::Aborted,
+            AssetError::MissingContent(_) => tonic::Code::FailedPrecondition,
+        }
+    }
+
+    pub fn to_status(&self) -> api::Status {
+        api::Status {
+            code: self.code() as i32,
+            message: self.to_string(),
+            details: vec![],
+        }
+    }
+}
+
+impl From<AssetError> for tonic::Status {
+    fn from(err: AssetError) -> Self {
+        let AssetError::UnsupportedQualifier(name) = &err else {
+            return tonic::Status::new(err.code(), err.to_string());
+        };
+        // Tells the client which qualifier to drop
+        let bad_request = api::BadRequest {
+            field_violations: vec![api::bad_request::FieldViolation {
+                field: String::from("qualifiers.name"),
+                description: format!("\"{}\" not supported", name),
+            }],
+        };
+        let status = api::Status {
+            details: vec![prost_types::Any {
+                type_url: String::from("type.googleapis.com/google.rpc.BadRequest"),
+                value: bad_request.encode_to_vec(),
+            }],
+            ..err.to_status()
+        };
+        tonic::Status::with_details(err.code(), err.to_string(), status.encode_to_vec().into())
+    }
+}
+
+/// An SRI checksum, e.g. `sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=`.
+///
+/// Several hashes may be listed, only those of the strongest algorithm count and the content
+/// must match one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checksum {
+    algorithm: Algorithm,
+    hashes: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Algorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Checksum {
+    pub fn parse(sri: &str) -> Result<Self, AssetError> {
+        let invalid = || AssetError::InvalidChecksum(sri.to_string());
+        let mut checksum: Option<Checksum> = None;
+        for hash in sri.split_whitespace() {
+            // Options after a `?` are reserved by the SRI spec and carry nothing for us
+            let hash = hash.split('?').next().unwrap_or_default();
+            let (algorithm, value) = hash.split_once('-').ok_or_else(invalid)?;
+            let algorithm = match algorithm {
+                "sha256" => Algorithm::Sha256,
+                "sha384" => Algorithm::Sha384,
+                "sha512" => Algorithm::Sha512,
+                _ => return Err(invalid()),
+            };
+            let value = base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .map_err(|_| invalid())?;
+            match &mut checksum {
+                Some(checksum) if checksum.algorithm == algorithm => checksum.hashes.push(value),
+                Some(checksum) if checksum.algorithm > algorithm => {}
+                _ => {
+                    checksum = Some(Checksum {
+                        algorithm,
+                        hashes: vec![value],
+                    })
+                }
+            }
+        }
+        checksum.ok_or_else(invalid)
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        let hash = match self.algorithm {
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+            Algorithm::Sha384 => Sha384::digest(data).to_vec(),
+            Algorithm::Sha512 => Sha512::digest(data).to_vec(),
+        };
+        self.hashes.contains(&hash)
+    }
+}
+
+/// Whether the qualifier only tells how to download from origin.
+pub fn is_ignored_qualifier(name: &str) -> bool {
+    IGNORED_QUALIFIERS.contains(&name)
+        || IGNORED_QUALIFIER_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+/// The checksum the content must match, if the request has one.
+pub fn checksum(qualifiers: &[api::Qualifier]) -> Result<Option<Checksum>, AssetError> {
+    qualifiers
+        .iter()
+        .rfind(|qualifier| qualifier.name == CHECKSUM_SRI)
+        .map(|qualifier| Checksum::parse(&qualifier.value))
+        .transpose()
+}
+
+/// Check that local copies can honor every qualifier of a request, pushed assets take any.
+pub fn check_supported(qualifiers: &[api::Qualifier]) -> Result<(), AssetError> {
+    match qualifiers
+        .iter()
+        .find(|qualifier| qualifier.name != CHECKSUM_SRI && !is_ignored_qualifier(&qualifier.name))
+    {
+        Some(qualifier) => Err(AssetError::UnsupportedQualifier(qualifier.name.clone())),
+        None => Ok(()),
+    }
+}
+
+/// A timestamp of a request, `field` names it in the error.
+pub fn system_time(
+    timestamp: Option<prost_types::Timestamp>,
+    field: &str,
+) -> Result<Option<SystemTime>, AssetError> {
+    timestamp
+        .map(SystemTime::try_from)
+        .transpose()
+        .map_err(|e| AssetError::InvalidTimestamp(field.to_string(), e.to_string()))
+}
+
+/// Serves assets from local copies.
+#[derive(Clone, Debug, Default)]
+pub struct AssetMirror {
+    mirror: Option<PathBuf>,
+    file_uris: bool,
+}
+
+impl AssetMirror {
+    pub fn new(config: Option<&FetchConfig>) -> Self {
+        match config {
+            Some(config) => AssetMirror {
+                mirror: config.mirror.clone(),
+                file_uris: config.file_uris,
+            },
+            None => AssetMirror::default(),
+        }
+    }
+
+    /// Where the local copy of `uri` lives.
+    fn locate(&self, uri: &str) -> Result<PathBuf, AssetError> {
+        let url =
+            Url::parse(uri).map_err(|e| AssetError::InvalidUri(uri.to_string(), e.to_string()))?;
+        let path = if url.scheme() == "file" {
+            if !self.file_uris {
+                return Err(AssetError::Disallowed(uri.to_string()));
+            }
+            url.to_file_path()
+                .map_err(|_| AssetError::InvalidUri(uri.to_string(), String::from("not a path")))?
+        } else {
+            let mirror = self
+                .mirror
+                .as_ref()
+                .ok_or_else(|| AssetError::Disallowed(uri.to_string()))?;
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_string(),
+                (None, _) => {
+                    return Err(AssetError::InvalidUri(
+                        uri.to_string(),
+                        String::from("no host"),
+                    ))
+                }
+            };
+            let relative =
+                resolve_in_root(&Path::new(&host).join(url.path().trim_start_matches('/')))
+                    .ok_or_else(|| {
+                        AssetError::InvalidUri(
+                            uri.to_string(),
+                            String::from("outside of the mirror"),
+                        )
+                    })?;
+            mirror.join(relative)
+        };
+        if !path.exists() {
+            return Err(AssetError::NotFound(uri.to_string()));
+        }
+        Ok(path)
+    }
+
+    /// Store the file behind `uri` in the CAS.
+    #[instrument(skip(self, cas, checksum))]
+    pub async fn fetch_blob(
+        &self,
+        cas: &ContentStorage,
+        instance: &str,
+        uri: &str,
+        checksum: Option<&Checksum>,
+    ) -> Result<api::Digest, AssetError> {
+        let path = self.locate(uri)?;
+        if path.is_dir() {
+            return Err(AssetError::NotFound(uri.to_string()));
+        }
+        let data = tokio::fs::read(&path).await?;
+        if checksum.is_some_and(|checksum| !checksum.matches(&data)) {
+            return Err(AssetError::ChecksumMismatch(uri.to_string()));
+        }
+        info!("Fetched {} from {}", uri, path.display());
+        Ok(cas.add_new_blob(instance, &data).await?)
+    }
+
+    /// Store the directory behind `uri` in the CAS, tar archives are unpacked first.
+    #[instrument(skip(self, cas, checksum))]
+    pub async fn fetch_directory(
+        &self,
+        cas: &ContentStorage,
+        instance: &str,
+        uri: &str,
+        checksum: Option<&Checksum>,
+    ) -> Result<api::Digest, AssetError> {
+        let path = self.locate(uri)?;
+        let unpacked;
+        let root = if path.is_dir() {
+            if checksum.is_some() {
+                return Err(AssetError::Unverifiable(uri.to_string()));
+            }
+            path
+        } else {
+            let name = path.to_string_lossy();
+            let gzipped = [".tar.gz", ".tgz"]
+                .iter()
+                .any(|suffix| name.ends_with(suffix));
+            if !gzipped && !name.ends_with(".tar") {
+                return Err(AssetError::NotADirectory(uri.to_string()));
+            }
+            let data = tokio::fs::read(&path).await?;
+            if checksum.is_some_and(|checksum| !checksum.matches(&data)) {
+                return Err(AssetError::ChecksumMismatch(uri.to_string()));
+            }
+            unpacked = tempfile::tempdir()?;
+            let dest = unpacked.path().to_path_buf();
+            tokio::task::spawn_blocking(move || unpack(&data, gzipped, &dest))
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))??;
+            unpacked.path().to_path_buf()
+        };
+
+        let mut children = vec![];
+        let dir = cas.add_directory(instance, root, &mut children).await?;
+        info!("Fetched {} with {} subdirectories", uri, children.len());
+        Ok(cas.add_new_blob(instance, &dir.encode_to_vec()).await?)
+    }
+}
+
+fn unpack(data: &[u8], gzipped: bool, dest: &Path) -> std::io::Result<()> {
+    let data = BufReader::new(data);
+    if gzipped {
+        tar::Archive::new(flate2::read::GzDecoder::new(data)).unpack(dest)
+    } else {
+        tar::Archive::new(data).unpack(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sri(algorithm: &str, hash: &[u8]) -> String {
+        format!(
+            "{}-{}",
+            algorithm,
+            base64::engine::general_purpose::STANDARD.encode(hash)
+        )
+    }
+
+    #[test]
+    fn parse_and_match() {
+        let checksum =
+            Checksum::parse("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap();
+        assert!(checksum.matches(b""));
+        assert!(!checksum.matches(b"hello"));
+
+        let checksum = Checksum::parse(&sri("sha384", &Sha384::digest(b"hello"))).unwrap();
+        assert!(checksum.matches(b"hello"));
+    }
+
+    #[test]
+    fn ignore_options() {
+        let sri = format!(
+            "{}?ct=application/octet-stream",
+            sri("sha256", &Sha256::digest(b"a"))
+        );
+        assert!(Checksum::parse(&sri).unwrap().matches(b"a"));
+    }
+
+    #[test]
+    fn any_hash_of_the_strongest_algorithm_matches() {
+        let list = [
+            sri("sha256", &Sha256::digest(b"a")),
+            sri("sha512", &Sha512::digest(b"b")),
+            sri("sha512", &Sha512::digest(b"c")),
+        ]
+        .join(" ");
+        let checksum = Checksum::parse(&list).unwrap();
+        assert!(checksum.matches(b"b"));
+        assert!(checksum.matches(b"c"));
+        // Weaker hashes are ignored once a stronger one is listed
+        assert!(!checksum.matches(b"a"));
+    }
+
+    #[test]
+    fn reject_invalid_checksums() {
+        for sri in [
+            "",
+            "sha256",
+            "md5-1B2M2Y8AsgTpgAmY7PhCfg==",
+            "sha256-not base64!",
+        ] {
+            assert!(
+                matches!(Checksum::parse(sri), Err(AssetError::InvalidChecksum(_))),
+                "{}",
+                sri
+            );
+        }
+    }
+}
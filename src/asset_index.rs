@@ -0,0 +1,290 @@
+//! Assets pushed through the Remote Asset API, kept on disk so they survive restarts.
+//!
+//! Every URI gets a JSON file listing the content pushed for it, one entry per set of
+//! qualifiers. Fetches are answered from it before anything else is tried.
+
+use crate::api;
+use crate::asset::{is_ignored_qualifier, AssetError, CHECKSUM_SRI};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use tracing::{info, instrument};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AssetKind {
+    Blob,
+    Directory,
+}
+
+/// Content associated with a URI.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Asset {
+    pub qualifiers: BTreeMap<String, String>,
+    pub hash: String,
+    pub size_bytes: i64,
+    pub pushed_at: SystemTime,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Asset {
+    pub fn digest(&self) -> api::Digest {
+        api::Digest {
+            hash: self.hash.clone(),
+            size_bytes: self.size_bytes,
+        }
+    }
+
+    pub fn qualifiers(&self) -> Vec<api::Qualifier> {
+        self.qualifiers
+            .iter()
+            .map(|(name, value)| api::Qualifier {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect()
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Qualifiers that tell apart the content of a URI, by name.
+fn significant(qualifiers: &[api::Qualifier]) -> BTreeMap<String, String> {
+    qualifiers
+        .iter()
+        .filter(|qualifier| !is_ignored_qualifier(&qualifier.name))
+        .map(|qualifier| (qualifier.name.clone(), qualifier.value.clone()))
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct AssetIndex {
+    dir: PathBuf,
+    /// Serializes updates, each rewrites a whole file.
+    writing: Arc<Mutex<()>>,
+}
+
+impl AssetIndex {
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(AssetIndex {
+            dir,
+            writing: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn path(&self, kind: AssetKind, instance: &str, uri: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{:?}\0{}\0{}", kind, instance, uri).as_bytes());
+        self.dir.join(format!(
+            "{}.json",
+            base16ct::lower::encode_string(&hasher.finalize())
+        ))
+    }
+
+    async fn read(&self, path: &Path) -> Result<Vec<Asset>, AssetError> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data).map_err(std::io::Error::from)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Associate `digest` with every URI, replacing what was pushed before with the same
+    /// qualifiers.
+    #[instrument(skip(self, qualifiers))]
+    pub async fn push(
+        &self,
+        kind: AssetKind,
+        instance: &str,
+        uris: &[String],
+        qualifiers: &[api::Qualifier],
+        digest: &api::Digest,
+        expires_at: Option<SystemTime>,
+    ) -> Result<(), AssetError> {
+        let now = SystemTime::now();
+        let asset = Asset {
+            qualifiers: significant(qualifiers),
+            hash: digest.hash.clone(),
+            size_bytes: digest.size_bytes,
+            pushed_at: now,
+            expires_at,
+        };
+        let _writing = self.writing.lock().await;
+        for uri in uris {
+            let path = self.path(kind, instance, uri);
+            let mut assets = self.read(&path).await?;
+            assets.retain(|old| old.qualifiers != asset.qualifiers && !old.is_expired(now));
+            assets.push(asset.clone());
+            // Written aside and renamed so readers never see half a file
+            let staging = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+            let data = serde_json::to_vec(&assets).map_err(std::io::Error::from)?;
+            tokio::fs::write(&staging, data).await?;
+            tokio::fs::rename(&staging, &path).await?;
+            info!("Pushed {} as {}", uri, digest.hash);
+        }
+        Ok(())
+    }
+
+    /// What was pushed for `uri` with at least the requested qualifiers, newest first.
+    ///
+    /// A `checksum.sri` qualifier only narrows the result when it was pushed along, otherwise
+    /// the caller has to check the content itself.
+    #[instrument(skip(self, qualifiers))]
+    pub async fn lookup(
+        &self,
+        kind: AssetKind,
+        instance: &str,
+        uri: &str,
+        qualifiers: &[api::Qualifier],
+        oldest_accepted: Option<SystemTime>,
+    ) -> Result<Vec<Asset>, AssetError> {
+        let now = SystemTime::now();
+        let requested = significant(qualifiers);
+        let mut assets = self.read(&self.path(kind, instance, uri)).await?;
+        assets.retain(|asset| {
+            !asset.is_expired(now)
+                && oldest_accepted.is_none_or(|oldest| asset.pushed_at >= oldest)
+                && requested
+                    .iter()
+                    .all(|(name, value)| match asset.qualifiers.get(name) {
+                        Some(pushed) => pushed == value,
+                        None => name == CHECKSUM_SRI,
+                    })
+        });
+        assets.sort_by_key(|asset| std::cmp::Reverse(asset.pushed_at));
+        Ok(assets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const URI: &str = "https://example.com/archive.tar.gz";
+
+    fn qualifiers(qualifiers: &[(&str, &str)]) -> Vec<api::Qualifier> {
+        qualifiers
+            .iter()
+            .map(|(name, value)| api::Qualifier {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn digest(hash: &str) -> api::Digest {
+        api::Digest {
+            hash: hash.to_string(),
+            size_bytes: 1,
+        }
+    }
+
+    fn hashes(assets: &[Asset]) -> Vec<&str> {
+        assets.iter().map(|asset| asset.hash.as_str()).collect()
+    }
+
+    async fn push(index: &AssetIndex, pushed: &[(&str, &str)], hash: &str) {
+        let uris = [URI.to_string()];
+        index
+            .push(
+                AssetKind::Blob,
+                "",
+                &uris,
+                &qualifiers(pushed),
+                &digest(hash),
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn lookup(index: &AssetIndex, requested: &[(&str, &str)]) -> Vec<Asset> {
+        index
+            .lookup(AssetKind::Blob, "", URI, &qualifiers(requested), None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lookup_by_qualifiers() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = AssetIndex::new(dir.path().to_path_buf()).unwrap();
+        push(&index, &[], "plain").await;
+        push(&index, &[("resource_type", "application/x-tar")], "tar").await;
+
+        assert_eq!(hashes(&lookup(&index, &[]).await), ["tar", "plain"]);
+        assert_eq!(
+            hashes(&lookup(&index, &[("resource_type", "application/x-tar")]).await),
+            ["tar"]
+        );
+        assert!(lookup(&index, &[("resource_type", "application/zip")])
+            .await
+            .is_empty());
+        // Qualifiers only describing the download are not part of the key
+        assert_eq!(
+            hashes(&lookup(&index, &[("bazel.canonical_id", "x")]).await),
+            ["tar", "plain"]
+        );
+        // Nor is a checksum that was not pushed
+        assert_eq!(
+            hashes(&lookup(&index, &[(CHECKSUM_SRI, "sha256-x")]).await),
+            ["tar", "plain"]
+        );
+    }
+
+    #[tokio::test]
+    async fn push_replaces_the_same_qualifiers() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = AssetIndex::new(dir.path().to_path_buf()).unwrap();
+        push(&index, &[("resource_type", "tar")], "old").await;
+        push(&index, &[("resource_type", "tar")], "new").await;
+        assert_eq!(hashes(&lookup(&index, &[]).await), ["new"]);
+
+        // Still there once reopened
+        let index = AssetIndex::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(hashes(&lookup(&index, &[]).await), ["new"]);
+    }
+
+    #[tokio::test]
+    async fn kinds_and_instances_are_separate() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = AssetIndex::new(dir.path().to_path_buf()).unwrap();
+        push(&index, &[], "blob").await;
+        let lookup = |kind, instance| index.lookup(kind, instance, URI, &[], None);
+        assert!(lookup(AssetKind::Directory, "").await.unwrap().is_empty());
+        assert!(lookup(AssetKind::Blob, "other").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skip_expired_and_stale_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = AssetIndex::new(dir.path().to_path_buf()).unwrap();
+        let uris = [URI.to_string()];
+        let now = SystemTime::now();
+        index
+            .push(
+                AssetKind::Blob,
+                "",
+                &uris,
+                &[],
+                &digest("expired"),
+                Some(now - Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        assert!(lookup(&index, &[]).await.is_empty());
+
+        push(&index, &[], "fresh").await;
+        let since = |oldest| index.lookup(AssetKind::Blob, "", URI, &[], Some(oldest));
+        assert_eq!(hashes(&since(now).await.unwrap()), ["fresh"]);
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(since(later).await.unwrap().is_empty());
+    }
+}
@@ -24,25 +24,40 @@ where
 {
     match task::spawn_blocking(f).await {
         Ok(res) => res,
-        Err(_) => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "background task failed",
-        )),
+        Err(_) => Err(io::Error::other("background task failed")),
     }
 }
 
 impl Blob {
     #[instrument]
-    /// Open a Blob object underneath the CAS root_fd.
+    /// Open a Blob object underneath the CAS root_fd, creating it if needed.
     ///
     /// TODO convert root_fd + instance to an instance_fd
     pub async fn open(root_fd: RawFd, instance: &str, hash: &str) -> Result<Blob, BlobError> {
+        Self::open_with_flags(root_fd, instance, hash, libc::O_RDWR | libc::O_CREAT).await
+    }
+
+    #[instrument]
+    /// Open a Blob object that must already be in the CAS for reading.
+    pub async fn open_existing(
+        root_fd: RawFd,
+        instance: &str,
+        hash: &str,
+    ) -> Result<Blob, BlobError> {
+        Self::open_with_flags(root_fd, instance, hash, libc::O_RDONLY).await
+    }
+
+    async fn open_with_flags(
+        root_fd: RawFd,
+        instance: &str,
+        hash: &str,
+        flags: libc::c_int,
+    ) -> Result<Blob, BlobError> {
         let path: PathBuf = [instance, hash].iter().collect();
         let file = asyncify(move || {
-            let mut how = OpenHow::new(
-                libc::O_RDWR | libc::O_CLOEXEC | libc::O_LARGEFILE | libc::O_CREAT,
-                0o644,
-            );
+            // openat2 only accepts a mode when creating
+            let mode = if flags & libc::O_CREAT != 0 { 0o644 } else { 0 };
+            let mut how = OpenHow::new(flags | libc::O_CLOEXEC | libc::O_LARGEFILE, mode);
             how.resolve |= ResolveFlags::NO_SYMLINKS;
             how.resolve |= ResolveFlags::IN_ROOT;
             let fd = openat2(Some(root_fd), path, &how)?;
@@ -57,4 +72,8 @@ impl Blob {
     pub fn file(&mut self) -> &mut fs::File {
         &mut self.file
     }
+
+    pub fn into_file(self) -> fs::File {
+        self.file
+    }
 }
@@ -0,0 +1,188 @@
+//! Server configuration, read from a TOML file.
+//!
+//! ```toml
+//! [platform.OSFamily]
+//! values = ["Linux"]
+//! default = "Linux"
+//!
+//! [platform.network]
+//! values = ["off", "on"]
+//! default = "off"
+//!
+//! # Host paths every action sees
+//! [[mounts]]
+//! source = "/usr/bin"
+//!
+//! [[mounts]]
+//! source = "/usr/local/include"
+//! optional = true
+//!
+//! # Picked with the `toolchain=gcc12` platform property
+//! [[toolchains.gcc12.mounts]]
+//! source = "/opt/gcc-12"
+//! target = "/usr/local/gcc"
+//!
+//! # Local OCI images, picked with `container-image=ubuntu-22.04` or `container-image=focal.tar`
+//! [images]
+//! dir = "/srv/images"
+//!
+//! # Remote Asset API downloads, https://example.com/a.tar.gz is served from
+//! # /srv/mirror/example.com/a.tar.gz
+//! [fetch]
+//! mirror = "/srv/mirror"
+//! file_uris = true
+//! ```
+//!
+//! Configuring toolchains makes `toolchain` a supported platform property, and configuring images
+//! does the same for `container-image`, unless the `platform` section already defines them.
+//! Actions running on an image see its filesystem in place of `mounts`, toolchains still apply.
+//! The image is read-only, so a toolchain target inside one of its directories has to exist in it.
+
+use crate::platform::{PropertyConfig, CONTAINER_IMAGE, TOOLCHAIN};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read {0}: {1}")]
+    Read(String, std::io::Error),
+
+    #[error("Invalid configuration in {0}: {1}")]
+    Parse(String, toml::de::Error),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Platform properties actions may ask for, by name.
+    #[serde(default)]
+    pub platform: BTreeMap<String, PropertyConfig>,
+
+    /// Host paths mounted into every sandbox.
+    #[serde(default = "default_mounts")]
+    pub mounts: Vec<MountConfig>,
+
+    /// Extra mounts selected with the `toolchain` platform property, by name.
+    #[serde(default)]
+    pub toolchains: BTreeMap<String, ToolchainConfig>,
+
+    /// Container images actions may run on instead of the host mounts.
+    pub images: Option<ImagesConfig>,
+
+    /// Where assets requested through the Remote Asset API come from.
+    pub fetch: Option<FetchConfig>,
+}
+
+/// A host path bound into the sandbox.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub source: PathBuf,
+    /// Where it shows up in the sandbox, the same as `source` when unset.
+    pub target: Option<PathBuf>,
+    #[serde(default = "default_readonly")]
+    pub readonly: bool,
+    /// Skip the mount when `source` does not exist instead of failing the action.
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl MountConfig {
+    pub fn target(&self) -> &Path {
+        self.target.as_deref().unwrap_or(&self.source)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ToolchainConfig {
+    pub mounts: Vec<MountConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImagesConfig {
+    /// OCI image layouts and tarballs of them, named by their path relative to this directory.
+    pub dir: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    /// Holds a copy of every remote URI, at `<host>/<path>` below it.
+    pub mirror: Option<PathBuf>,
+    /// Serve `file://` URIs from the server's own filesystem.
+    #[serde(default)]
+    pub file_uris: bool,
+}
+
+fn default_readonly() -> bool {
+    true
+}
+
+/// Enough of the host to run common Linux binaries.
+fn default_mounts() -> Vec<MountConfig> {
+    let mount = |source: &str, optional| MountConfig {
+        source: PathBuf::from(source),
+        target: None,
+        readonly: true,
+        optional,
+    };
+    vec![
+        mount("/usr/bin", false),
+        mount("/usr/lib", false),
+        mount("/usr/lib64", true),
+        mount("/usr/include", true),
+        mount("/usr/local/include", true),
+        mount("/lib64/ld-linux-x86-64.so.2", true),
+    ]
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.display().to_string(), e))?;
+        let mut config: Config =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.display().to_string(), e))?;
+        if !config.toolchains.is_empty() {
+            let toolchains = config.toolchains.keys().cloned().collect();
+            config
+                .platform
+                .entry(String::from(TOOLCHAIN))
+                .or_insert(PropertyConfig {
+                    values: toolchains,
+                    default: None,
+                });
+        }
+        if config.images.is_some() {
+            config
+                .platform
+                .entry(String::from(CONTAINER_IMAGE))
+                .or_default();
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    /// Used without a configuration file: plain Linux actions without network access.
+    fn default() -> Self {
+        let mut platform = BTreeMap::new();
+        platform.insert(
+            String::from("OSFamily"),
+            PropertyConfig {
+                values: vec![String::from("Linux")],
+                default: Some(String::from("Linux")),
+            },
+        );
+        Config {
+            platform,
+            mounts: default_mounts(),
+            toolchains: BTreeMap::new(),
+            images: None,
+            fetch: None,
+        }
+    }
+}
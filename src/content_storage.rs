@@ -1,17 +1,19 @@
 use crate::api;
+use futures::future::BoxFuture;
 use openat2::*;
-use prost::DecodeError;
+use prost::{DecodeError, Message};
 use sha2::{Digest, Sha256};
 use std::io;
 use std::os::fd::RawFd;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{info, instrument};
 use uuid::Uuid;
 
-use crate::blob::Blob;
+use crate::blob::{Blob, BlobError};
 
 #[derive(Error, Debug)]
 pub enum CasError {
@@ -27,8 +29,47 @@ pub enum CasError {
     #[error("Proto did not decode cleanly: {0}")]
     InvalidProto(DecodeError),
 
-    #[error("unknown data store error")]
-    Unknown,
+    #[error("Blob {0} is missing from the CAS")]
+    MissingBlob(String),
+
+    #[error("Instance name {0} is not a relative path")]
+    InvalidInstance(String),
+
+    #[error("{0} is not a regular file, directory or symlink")]
+    UnsupportedFile(PathBuf),
+}
+
+impl From<CasError> for tonic::Status {
+    fn from(err: CasError) -> Self {
+        let code = match err {
+            CasError::MissingBlob(_) => tonic::Code::NotFound,
+            CasError::InvalidInstance(_) | CasError::UnsupportedFile(_) => {
+                tonic::Code::InvalidArgument
+            }
+            _ => tonic::Code::Internal,
+        };
+        tonic::Status::new(code, err.to_string())
+    }
+}
+
+/// Size of the pieces blobs are streamed in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Read the next piece of a blob, shorter than `CHUNK_SIZE` only at its end.
+pub async fn read_chunk(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    file.take(CHUNK_SIZE as u64).read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+/// The digest `data` is stored under.
+pub fn digest_of(data: &[u8]) -> api::Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    api::Digest {
+        hash: base16ct::lower::encode_string(&hasher.finalize()),
+        size_bytes: data.len() as i64,
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -41,8 +82,12 @@ impl ContentStorage {
     #[instrument]
     pub fn new(root_path: PathBuf) -> Result<Self, CasError> {
         let root_path = std::fs::canonicalize(root_path)?;
+        if !root_path.is_dir() {
+            return Err(CasError::NotDirectory);
+        }
         // TODO make a root handle for each instance instead of just one per CAS
-        std::fs::create_dir_all(&root_path.join("remote-execution"))?;
+        std::fs::create_dir_all(root_path.join("remote-execution"))?;
+        std::fs::create_dir_all(root_path.join("uploads"))?;
         info!("Storage: {}", std::fs::canonicalize(&root_path)?.display());
 
         let mut how = OpenHow::new(libc::O_CLOEXEC | libc::O_DIRECTORY, 0);
@@ -66,11 +111,60 @@ impl ContentStorage {
         let mut blob = self.get_blob(instance, &digest.hash).await?;
         let mut buf = vec![];
         blob.file().read_to_end(&mut buf).await?;
-        T::decode(&mut std::io::Cursor::new(buf)).map_err(|e| CasError::InvalidProto(e))
+        T::decode(&mut std::io::Cursor::new(buf)).map_err(CasError::InvalidProto)
     }
 
+    /// Whether the blob is stored in full, without creating it if it is not.
+    #[instrument(skip(self))]
+    pub async fn has_blob(&self, instance: &str, digest: &api::Digest) -> Result<bool, CasError> {
+        let mut blob = match self.get_blob(instance, &digest.hash).await {
+            Ok(blob) => blob,
+            Err(CasError::MissingBlob(_)) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let len = blob.file().metadata().await?.len();
+        Ok(len == digest.size_bytes as u64)
+    }
+
+    /// Path to a copy of the blob with its executable bits set, made on first use so the shared
+    /// blob keeps its 0644 mode.
+    #[instrument(skip(self))]
+    pub async fn get_executable_path(
+        &self,
+        instance: &str,
+        digest: &api::Digest,
+    ) -> Result<PathBuf, CasError> {
+        let dir = self.root_path.join("executables").join(instance);
+        let path = dir.join(&digest.hash);
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(path);
+        }
+        tokio::fs::create_dir_all(&dir).await?;
+        // Copied aside and renamed into place so concurrent actions never see a partial copy
+        let temp_path = dir.join(format!("{}.{}", digest.hash, Uuid::new_v4()));
+        let mut blob = self.get_blob(instance, &digest.hash).await?;
+        let mut copy = File::create(&temp_path).await?;
+        tokio::io::copy(blob.file(), &mut copy).await?;
+        copy.set_permissions(std::fs::Permissions::from_mode(0o755))
+            .await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(path)
+    }
+
+    /// Open a blob that is already stored.
     #[instrument(skip(self))]
     async fn get_blob(&self, instance: &str, hash: &str) -> Result<Blob, CasError> {
+        match Blob::open_existing(self.root_fd, instance, hash).await {
+            Err(BlobError::OpenError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                Err(CasError::MissingBlob(hash.to_string()))
+            }
+            res => res.map_err(Into::into),
+        }
+    }
+
+    /// Open a blob for writing, creating it if it is not stored yet.
+    #[instrument(skip(self))]
+    async fn create_blob(&self, instance: &str, hash: &str) -> Result<Blob, CasError> {
         Blob::open(self.root_fd, instance, hash)
             .await
             .map_err(Into::into)
@@ -87,41 +181,109 @@ impl ContentStorage {
         let mut file = File::open(path).await?;
         let mut buf = vec![];
         file.read_to_end(&mut buf).await?;
+        self.add_new_blob(instance, &buf).await
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(&buf);
-        let hash_buf = hasher.finalize();
-        let hex_hash = base16ct::lower::encode_string(&hash_buf);
-        info!("hash: {}", hex_hash);
-        let mut blob = self.get_blob(instance, &hex_hash).await?;
+    /// Add a blob held in memory, such as an encoded proto.
+    #[instrument(skip(self, data))]
+    pub async fn add_new_blob(&self, instance: &str, data: &[u8]) -> Result<api::Digest, CasError> {
+        let digest = digest_of(data);
+        info!("hash: {}", digest.hash);
+        let mut blob = self.create_blob(instance, &digest.hash).await?;
 
-        let _ = blob.file().write(&buf).await?;
+        blob.file().write_all(data).await?;
         blob.file().flush().await?;
-        Ok(api::Digest {
-            size_bytes: buf.len() as i64,
-            hash: hex_hash.to_string(),
+        Ok(digest)
+    }
+
+    /// Store every file below `path` along with the Directory protos of its subdirectories,
+    /// which are also collected in `children`. The returned root Directory is not stored.
+    pub fn add_directory<'a>(
+        &'a self,
+        instance: &'a str,
+        path: PathBuf,
+        children: &'a mut Vec<(api::Digest, api::Directory)>,
+    ) -> BoxFuture<'a, Result<api::Directory, CasError>> {
+        Box::pin(async move {
+            let mut entries = vec![];
+            let mut read_dir = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                entries.push(entry);
+            }
+            // Directory protos must list their entries sorted by name
+            entries.sort_by_key(|entry| entry.file_name());
+
+            let mut dir = api::Directory::default();
+            for entry in entries {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let entry_path = entry.path();
+                let file_type = entry.file_type().await?;
+                if file_type.is_symlink() {
+                    // Kept as a link, the client decides how to materialize it
+                    let target = tokio::fs::read_link(&entry_path).await?;
+                    dir.symlinks.push(api::SymlinkNode {
+                        name,
+                        target: target.to_string_lossy().into_owned(),
+                        node_properties: None,
+                    });
+                } else if file_type.is_dir() {
+                    let child = self.add_directory(instance, entry_path, children).await?;
+                    let digest = self.add_new_blob(instance, &child.encode_to_vec()).await?;
+                    children.push((digest.clone(), child));
+                    dir.directories.push(api::DirectoryNode {
+                        name,
+                        digest: Some(digest),
+                    });
+                } else if file_type.is_file() {
+                    let digest = self.add_new_blob_from_file(instance, &entry_path).await?;
+                    dir.files.push(api::FileNode {
+                        name,
+                        digest: Some(digest),
+                        is_executable: entry.metadata().await?.permissions().mode() & 0o111 != 0,
+                        node_properties: None,
+                    });
+                } else {
+                    // Reading a FIFO or device could block forever or never end
+                    return Err(CasError::UnsupportedFile(entry_path));
+                }
+            }
+            Ok(dir)
         })
     }
 
-    /// Write the specified blob of data to the file.
-    #[instrument(skip(self, data))]
-    pub async fn write_data(
-        &self,
-        instance: &str,
-        uuid: Uuid,
-        hash: &str,
-        size: usize,
-        write_offset: i64,
-        finish_write: bool,
-        data: &[u8],
-    ) -> Result<usize, CasError> {
-        let mut blob = self.get_blob(instance, hash).await?;
-        // only support really small stuff right now
-        assert!(finish_write);
+    /// Start writing a blob whose digest is only known once all of it is written.
+    #[instrument(skip(self))]
+    pub async fn blob_writer(&self, instance: &str) -> Result<BlobWriter, CasError> {
+        // Unlike blobs, which are opened beneath the root, the finished upload is renamed by path
+        if !Path::new(instance)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(CasError::InvalidInstance(instance.to_string()));
+        }
+        let temp_path = self
+            .root_path
+            .join("uploads")
+            .join(Uuid::new_v4().to_string());
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(&temp_path)
+            .await?;
+        Ok(BlobWriter {
+            file,
+            temp_path,
+            dir: self.root_path.join(instance),
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
 
-        let bytes_written = blob.file().write(data).await?;
-        blob.file().flush().await?;
-        Ok(bytes_written)
+    /// Open a stored blob for reading.
+    #[instrument(skip(self))]
+    pub async fn open_blob(&self, instance: &str, hash: &str) -> Result<File, CasError> {
+        Ok(self.get_blob(instance, hash).await?.into_file())
     }
 
     #[instrument(skip(self))]
@@ -132,3 +294,46 @@ impl ContentStorage {
         Ok(buf)
     }
 }
+
+/// A blob written in pieces, only stored under its digest once finished so a partial upload
+/// is never mistaken for the blob. Dropped unfinished, it is discarded.
+pub struct BlobWriter {
+    file: File,
+    temp_path: PathBuf,
+    dir: PathBuf,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl BlobWriter {
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), CasError> {
+        self.file.write_all(data).await?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Store the blob under the digest of what was written, which is returned.
+    pub async fn finish(mut self) -> Result<api::Digest, CasError> {
+        self.file.flush().await?;
+        let digest = api::Digest {
+            hash: base16ct::lower::encode_string(&std::mem::take(&mut self.hasher).finalize()),
+            size_bytes: self.size as i64,
+        };
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::rename(&self.temp_path, self.dir.join(&digest.hash)).await?;
+        Ok(digest)
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        // Already gone once finished
+        let _ = std::fs::remove_file(&self.temp_path);
+    }
+}
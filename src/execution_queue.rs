@@ -0,0 +1,304 @@
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum QueueError {
+    #[error("Execution queue is full, {0} actions are already waiting")]
+    Full(usize),
+}
+
+struct Waiter {
+    priority: i32,
+    seq: u64,
+    enqueued: Instant,
+    tx: oneshot::Sender<Slot>,
+}
+
+struct State {
+    free_slots: usize,
+    next_seq: u64,
+    waiting: Vec<Waiter>,
+    /// Waiting time that earns a queued action one step of priority.
+    aging: Duration,
+}
+
+impl State {
+    /// Remove the most urgent waiter: lowest priority value after aging, oldest first.
+    fn pop_next(&mut self) -> Option<Waiter> {
+        let now = Instant::now();
+        let next = self
+            .waiting
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, waiter)| {
+                let priority =
+                    effective_priority(waiter.priority, waiter.enqueued, now, self.aging);
+                (priority, waiter.seq)
+            })
+            .map(|(i, _)| i)?;
+        Some(self.waiting.swap_remove(next))
+    }
+}
+
+/// Priority of an action queued at `enqueued` as of `now`, one step more urgent for every
+/// `aging` it waited.
+pub fn effective_priority(priority: i32, enqueued: Instant, now: Instant, aging: Duration) -> i64 {
+    let waited = now.duration_since(enqueued).as_millis() / aging.as_millis().max(1);
+    priority as i64 - waited.min(i64::MAX as u128) as i64
+}
+
+/// Number of actions the host can run at once, one per CPU as long as each gets
+/// `memory_per_slot` bytes of RAM.
+pub fn host_slots(memory_per_slot: u64) -> usize {
+    let cpus = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let memory = unsafe {
+        let mut info: libc::sysinfo = std::mem::zeroed();
+        if libc::sysinfo(&mut info) == 0 {
+            info.totalram as u64 * info.mem_unit as u64
+        } else {
+            u64::MAX
+        }
+    };
+    let by_memory = (memory / memory_per_slot.max(1)) as usize;
+    cpus.min(by_memory).max(1)
+}
+
+/// Priority queue handing out a fixed number of execution slots.
+///
+/// Lower priority values run first, FIFO within a priority. Waiting actions gain priority over
+/// time so a steady stream of urgent work cannot starve the rest.
+#[derive(Clone)]
+pub struct ExecutionQueue {
+    state: Arc<Mutex<State>>,
+    max_queued: usize,
+    priorities: RangeInclusive<i32>,
+}
+
+impl ExecutionQueue {
+    pub fn new(
+        slots: usize,
+        max_queued: usize,
+        priorities: RangeInclusive<i32>,
+        aging: Duration,
+    ) -> Self {
+        info!("{} execution slots, up to {} queued", slots, max_queued);
+        ExecutionQueue {
+            state: Arc::new(Mutex::new(State {
+                free_slots: slots,
+                next_seq: 0,
+                waiting: vec![],
+                aging,
+            })),
+            max_queued,
+            priorities,
+        }
+    }
+
+    /// The supported priority range, anything outside of it is clamped.
+    pub fn priorities(&self) -> &RangeInclusive<i32> {
+        &self.priorities
+    }
+
+    /// Take a place in the queue, failing if too many actions are already waiting.
+    pub fn enqueue(&self, priority: i32) -> Result<Ticket, QueueError> {
+        let priority = priority.clamp(*self.priorities.start(), *self.priorities.end());
+        let mut state = self.state.lock().unwrap();
+        // Actions cancelled while queued leave their place behind
+        state.waiting.retain(|waiter| !waiter.tx.is_closed());
+        if state.free_slots > 0 && state.waiting.is_empty() {
+            state.free_slots -= 1;
+            return Ok(Ticket::Ready(Slot {
+                state: Some(self.state.clone()),
+            }));
+        }
+        if state.waiting.len() >= self.max_queued {
+            return Err(QueueError::Full(state.waiting.len()));
+        }
+        let (tx, rx) = oneshot::channel();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.waiting.push(Waiter {
+            priority,
+            seq,
+            enqueued: Instant::now(),
+            tx,
+        });
+        Ok(Ticket::Waiting(rx))
+    }
+}
+
+/// A place in the queue, redeemed for a [`Slot`] once one frees up.
+pub enum Ticket {
+    Ready(Slot),
+    Waiting(oneshot::Receiver<Slot>),
+}
+
+impl Ticket {
+    pub async fn slot(self) -> Slot {
+        match self {
+            Ticket::Ready(slot) => slot,
+            // The queue never drops a sender without sending a slot
+            Ticket::Waiting(rx) => rx.await.expect("execution queue dropped a waiter"),
+        }
+    }
+}
+
+/// Permission to run one action, handed to the next waiter when dropped.
+pub struct Slot {
+    state: Option<Arc<Mutex<State>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            release(state);
+        }
+    }
+}
+
+fn release(state: Arc<Mutex<State>>) {
+    loop {
+        let next = {
+            let mut locked = state.lock().unwrap();
+            match locked.pop_next() {
+                Some(waiter) => waiter.tx,
+                None => {
+                    locked.free_slots += 1;
+                    return;
+                }
+            }
+        };
+        // Sent outside the lock, a slot bounced back by a gone waiter must not release again
+        match next.send(Slot {
+            state: Some(state.clone()),
+        }) {
+            Ok(()) => return,
+            Err(mut slot) => slot.state = None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(slots: usize, max_queued: usize) -> ExecutionQueue {
+        ExecutionQueue::new(slots, max_queued, 0..=10, Duration::from_secs(60))
+    }
+
+    fn waiting(ticket: Ticket) -> oneshot::Receiver<Slot> {
+        match ticket {
+            Ticket::Waiting(rx) => rx,
+            Ticket::Ready(_) => panic!("expected to wait for a slot"),
+        }
+    }
+
+    #[test]
+    fn hand_out_free_slots() {
+        let queue = queue(2, 0);
+        let first = queue.enqueue(0).unwrap();
+        let second = queue.enqueue(0).unwrap();
+        assert!(matches!(first, Ticket::Ready(_)));
+        assert!(matches!(second, Ticket::Ready(_)));
+    }
+
+    #[test]
+    fn reject_when_full() {
+        let queue = queue(0, 1);
+        let _waiting = queue.enqueue(0).unwrap();
+        assert!(matches!(queue.enqueue(0), Err(QueueError::Full(1))));
+    }
+
+    #[test]
+    fn released_slot_goes_to_the_oldest_waiter() {
+        let queue = queue(1, 10);
+        let Ok(Ticket::Ready(slot)) = queue.enqueue(0) else {
+            panic!("expected a free slot");
+        };
+        let mut first = waiting(queue.enqueue(0).unwrap());
+        let mut second = waiting(queue.enqueue(0).unwrap());
+        drop(slot);
+        let _running = first.try_recv().unwrap();
+        assert!(second.try_recv().is_err());
+    }
+
+    #[test]
+    fn cancelled_waiters_leave_room() {
+        let queue = queue(0, 1);
+        drop(queue.enqueue(0).unwrap());
+        assert!(queue.enqueue(0).is_ok());
+    }
+
+    #[test]
+    fn clamp_priorities() {
+        let queue = queue(0, 10);
+        let _low = queue.enqueue(-100).unwrap();
+        let _high = queue.enqueue(100).unwrap();
+        let state = queue.state.lock().unwrap();
+        let priorities: Vec<i32> = state.waiting.iter().map(|waiter| waiter.priority).collect();
+        assert_eq!(priorities, [0, 10]);
+    }
+
+    #[test]
+    fn released_slot_goes_to_the_most_urgent_waiter() {
+        let queue = queue(1, 10);
+        let Ok(Ticket::Ready(slot)) = queue.enqueue(0) else {
+            panic!("expected a free slot");
+        };
+        let mut later = waiting(queue.enqueue(5).unwrap());
+        let mut urgent = waiting(queue.enqueue(1).unwrap());
+        drop(slot);
+        let _running = urgent.try_recv().unwrap();
+        assert!(later.try_recv().is_err());
+    }
+
+    #[test]
+    fn age_priorities() {
+        let now = Instant::now();
+        let aging = Duration::from_secs(1);
+        assert_eq!(effective_priority(5, now, now, aging), 5);
+        assert_eq!(effective_priority(5, now, now + aging * 3, aging), 2);
+        assert_eq!(effective_priority(5, now, now + aging * 10, aging), -5);
+        // Aging is counted in whole steps, and never divides by zero
+        assert_eq!(effective_priority(5, now, now + aging / 2, aging), 5);
+        assert_eq!(
+            effective_priority(5, now, now + Duration::from_millis(3), Duration::ZERO),
+            2
+        );
+    }
+
+    #[test]
+    fn long_waiters_overtake_urgent_ones() {
+        let now = Instant::now();
+        let (old, _old) = oneshot::channel();
+        let (new, _new) = oneshot::channel();
+        let mut state = State {
+            free_slots: 0,
+            next_seq: 2,
+            waiting: vec![
+                Waiter {
+                    priority: 10,
+                    seq: 0,
+                    enqueued: now - Duration::from_secs(20),
+                    tx: old,
+                },
+                Waiter {
+                    priority: 0,
+                    seq: 1,
+                    enqueued: now,
+                    tx: new,
+                },
+            ],
+            aging: Duration::from_secs(1),
+        };
+        assert_eq!(state.pop_next().map(|waiter| waiter.seq), Some(0));
+        assert_eq!(state.pop_next().map(|waiter| waiter.seq), Some(1));
+        assert!(state.pop_next().is_none());
+    }
+}
@@ -1,11 +1,15 @@
+use crate::api;
 use crate::content_storage::CasError;
-use crate::sandboxed_action::SandboxedActionResp;
+use crate::execution_queue::{ExecutionQueue, QueueError};
+use crate::image::ImageError;
+use crate::log_stream::LogStreamError;
+use crate::operation_registry::OperationRegistry;
+use crate::platform::PlatformError;
+use crate::sandboxed_action::SandboxError;
 use futures::Future;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use prost::Message;
 use thiserror::Error;
-use tokio_stream::Stream;
-use uuid::Uuid;
+use tracing::info;
 
 #[derive(Error, Debug)]
 pub enum ActionError {
@@ -13,67 +17,183 @@ pub enum ActionError {
     SandboxIoError(#[from] std::io::Error),
     #[error("CAS: {0}")]
     CasError(#[from] CasError),
-    #[error("Unknown")]
-    Unknown,
+    #[error("{0}")]
+    SandboxError(#[from] SandboxError),
+    #[error("Invalid Action: {0}")]
+    InvalidAction(String),
+    #[error("Invalid Command: {0}")]
+    InvalidCommand(String),
+    #[error("{0}")]
+    Platform(#[from] PlatformError),
+    #[error("{} input blobs are missing from the CAS", .0.len())]
+    MissingInputs(Vec<api::Digest>),
+    #[error("{0}")]
+    Image(#[from] ImageError),
+    #[error("{0}")]
+    LogStream(#[from] LogStreamError),
+    #[error("Scheduler: {}", .0.message())]
+    Remote(Box<tonic::Status>),
 }
 
-#[derive(Clone, Debug)]
-pub enum Stage {
-    Queued,
-    Executing,
-    Completed(SandboxedActionResp),
+impl From<tonic::Status> for ActionError {
+    fn from(status: tonic::Status) -> Self {
+        ActionError::Remote(Box::new(status))
+    }
 }
 
-pub struct ActionStatus {
-    future: Pin<Box<dyn Future<Output = Result<SandboxedActionResp, ActionError>> + Send>>,
-    stage: Stage,
-}
+impl ActionError {
+    /// The gRPC code that describes this failure to the client.
+    pub fn code(&self) -> tonic::Code {
+        match self {
+            ActionError::CasError(CasError::MissingBlob(_)) | ActionError::MissingInputs(_) => {
+                tonic::Code::FailedPrecondition
+            }
+            ActionError::CasError(CasError::InvalidProto(_))
+            | ActionError::CasError(CasError::UnsupportedFile(_)) => tonic::Code::InvalidArgument,
+            ActionError::SandboxError(SandboxError::InvalidCommand(_))
+            | ActionError::SandboxError(SandboxError::Exec(..)) => tonic::Code::InvalidArgument,
+            ActionError::InvalidAction(_)
+            | ActionError::InvalidCommand(_)
+            | ActionError::Platform(_)
+            | ActionError::Image(ImageError::NotFound(_))
+            | ActionError::Image(ImageError::Invalid(..)) => tonic::Code::InvalidArgument,
+            ActionError::SandboxIoError(_)
+            | ActionError::Image(_)
+            | ActionError::CasError(_)
+            | ActionError::SandboxError(_)
+            | ActionError::LogStream(_) => tonic::Code::Internal,
+            // The worker lost touch with the scheduler, running the action again may work
+            ActionError::Remote(_) => tonic::Code::Unavailable,
+        }
+    }
 
-impl Stream for ActionStatus {
-    type Item = Stage;
+    pub fn to_status(&self) -> api::Status {
+        let mut details = vec![];
+        if let ActionError::MissingInputs(digests) = self {
+            // Tells the client exactly which blobs to upload before retrying
+            let failure = api::PreconditionFailure {
+                violations: digests
+                    .iter()
+                    .map(|digest| api::precondition_failure::Violation {
+                        r#type: String::from("MISSING"),
+                        subject: format!("blobs/{}/{}", digest.hash, digest.size_bytes),
+                        description: String::new(),
+                    })
+                    .collect(),
+            };
+            details.push(prost_types::Any {
+                type_url: String::from("type.googleapis.com/google.rpc.PreconditionFailure"),
+                value: failure.encode_to_vec(),
+            });
+        }
+        api::Status {
+            code: self.code() as i32,
+            message: self.to_string(),
+            details,
+        }
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if let Stage::Completed(_) = self.stage {
-            return Poll::Ready(None);
+    /// The response of an action that could not run.
+    pub fn to_response(&self) -> api::ExecuteResponse {
+        api::ExecuteResponse {
+            status: Some(self.to_status()),
+            ..Default::default()
         }
-        match Pin::new(&mut self.future).poll(cx) {
-            Poll::Ready(s) => match s {
-                Ok(s) => {
-                    self.stage = Stage::Completed(s);
-                    Poll::Ready(Some(self.stage.clone()))
-                }
-                Err(_) => todo!(),
-            },
-            Poll::Pending => match self.stage {
-                Stage::Queued => {
-                    self.stage = Stage::Executing;
-                    Poll::Ready(Some(self.stage.clone()))
-                }
-                Stage::Executing => Poll::Pending,
-                Stage::Completed(_) => Poll::Ready(None),
-            },
+    }
+}
+
+impl From<ActionError> for tonic::Status {
+    fn from(err: ActionError) -> Self {
+        let status = err.to_status();
+        if status.details.is_empty() {
+            return tonic::Status::new(err.code(), status.message);
         }
+        tonic::Status::with_details(
+            err.code(),
+            status.message.clone(),
+            status.encode_to_vec().into(),
+        )
+    }
+}
+
+/// Lets a running action tell the clients of its operation how far along it is.
+pub struct Progress {
+    operations: OperationRegistry,
+    name: String,
+}
+
+impl Progress {
+    pub fn set_stage(&self, stage: api::execution_stage::Value) {
+        self.operations.set_stage(&self.name, stage);
     }
 }
 
-pub struct ExecutionRunner {}
+pub struct ExecutionRunner {
+    operations: OperationRegistry,
+    queue: ExecutionQueue,
+}
 
 impl ExecutionRunner {
-    pub fn new() -> Self {
-        ExecutionRunner {}
+    pub fn new(operations: OperationRegistry, queue: ExecutionQueue) -> Self {
+        ExecutionRunner { operations, queue }
+    }
+
+    pub fn operations(&self) -> &OperationRegistry {
+        &self.operations
     }
 
-    pub fn queue<F>(&self, future: F) -> (Uuid, ActionStatus)
+    /// Register an operation for the action and start it in the background once an execution
+    /// slot is free, driving it to completion. Lower `priority` values are scheduled first.
+    ///
+    /// The operation stays queued until the action reports otherwise through its [`Progress`].
+    /// Returns the operation name, progress can be followed through the registry.
+    pub fn queue<A, F>(
+        &self,
+        action_digest: api::Digest,
+        priority: i32,
+        action: A,
+    ) -> Result<String, QueueError>
     where
-        F: Future<Output = Result<SandboxedActionResp, ActionError>> + Send + 'static,
+        A: FnOnce(Progress) -> F + Send + 'static,
+        F: Future<Output = Result<api::ExecuteResponse, ActionError>> + Send + 'static,
     {
-        let uuid = Uuid::new_v4();
-        (
-            uuid,
-            ActionStatus {
-                future: Box::pin(future),
-                stage: Stage::Queued,
-            },
-        )
+        let ticket = self.queue.enqueue(priority)?;
+        let name = self.operations.create(action_digest);
+        let operations = self.operations.clone();
+        let op_name = name.clone();
+        let progress = Progress {
+            operations: operations.clone(),
+            name: name.clone(),
+        };
+        tokio::spawn(async move {
+            let execution = async {
+                let _slot = ticket.slot().await;
+                action(progress).await
+            };
+            // Dropping the action future tears down its sandbox.
+            let result = tokio::select! {
+                result = execution => result,
+                _ = operations.cancelled(&op_name) => {
+                    info!("Operation {} cancelled", op_name);
+                    Ok(api::ExecuteResponse {
+                        status: Some(api::Status {
+                            code: tonic::Code::Cancelled as i32,
+                            message: String::from("operation was cancelled"),
+                            details: vec![],
+                        }),
+                        ..Default::default()
+                    })
+                }
+            };
+            let response = match result {
+                Ok(response) => response,
+                Err(err) => {
+                    info!("Action failed: {}", err);
+                    err.to_response()
+                }
+            };
+            operations.complete(&op_name, response);
+        });
+        Ok(name)
     }
 }
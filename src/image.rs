@@ -0,0 +1,281 @@
+//! Root filesystems for actions, unpacked from local OCI images.
+//!
+//! An image is either an [OCI image layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md)
+//! directory or a tarball of one. Layers are applied in order once and the result is cached,
+//! keyed by the image manifest digest.
+
+use crate::sandboxed_action::{check_parents, remove_path, resolve_in_root};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{info, instrument};
+
+/// Marks a directory whose contents in lower layers are hidden.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// Prefix of an entry removing the file of the same name in lower layers.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("I/O: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Container image {0} was not found")]
+    NotFound(String),
+
+    #[error("Invalid container image {0}: {1}")]
+    Invalid(String, String),
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+}
+
+/// Images available to actions, looked up by path below `dir`.
+#[derive(Clone, Debug)]
+pub struct ImageStore {
+    dir: PathBuf,
+    cache: PathBuf,
+    /// One lock per image path, held while unpacking it so concurrent actions wait for the
+    /// first to finish instead of unpacking it again.
+    unpacking: Arc<std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>>,
+}
+
+impl ImageStore {
+    pub fn new(dir: PathBuf, cache: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&cache)?;
+        Ok(ImageStore {
+            dir,
+            // Root filesystems are mounted from inside the sandbox, relative paths would not work
+            cache: std::fs::canonicalize(cache)?,
+            unpacking: Arc::default(),
+        })
+    }
+
+    /// The unpacked root filesystem of an image, relative to the image directory.
+    #[instrument(skip(self))]
+    pub async fn rootfs(&self, reference: &str) -> Result<PathBuf, ImageError> {
+        let path = resolve_in_root(Path::new(reference))
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| self.dir.join(path))
+            .ok_or_else(|| ImageError::NotFound(reference.to_string()))?;
+        let (cache, image) = (self.cache.clone(), path.clone());
+        let rootfs = blocking({
+            let reference = reference.to_string();
+            move || cached_rootfs(&reference, &image, &cache)
+        })
+        .await?;
+        // Images already unpacked are served without waiting on anyone
+        if !rootfs.exists() {
+            let lock = self
+                .unpacking
+                .lock()
+                .unwrap()
+                .entry(path.clone())
+                .or_default()
+                .clone();
+            let _unpacking = lock.lock().await;
+            let (reference, rootfs) = (reference.to_string(), rootfs.clone());
+            blocking(move || unpack_image(&reference, &path, &rootfs)).await?;
+        }
+        if !rootfs.is_dir() {
+            return Err(ImageError::Invalid(
+                reference.to_string(),
+                String::from("unpacked image is not a directory"),
+            ));
+        }
+        Ok(rootfs)
+    }
+}
+
+async fn blocking<T, F>(f: F) -> Result<T, ImageError>
+where
+    F: FnOnce() -> Result<T, ImageError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+}
+
+/// Where the image at `path` is unpacked in the cache, whether or not it is yet.
+fn cached_rootfs(reference: &str, path: &Path, cache: &Path) -> Result<PathBuf, ImageError> {
+    let meta = std::fs::metadata(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ImageError::NotFound(reference.to_string()),
+        _ => e.into(),
+    })?;
+    if meta.is_file() {
+        // Tarballs are keyed by the file itself, the manifest is only known once extracted
+        let key = hex_digest(format!(
+            "{}:{}:{}",
+            path.display(),
+            meta.len(),
+            meta.mtime()
+        ));
+        return Ok(cache.join(key));
+    }
+    let manifest = manifest_digest(path).map_err(|e| with_reference(e, reference))?;
+    Ok(cache.join(manifest.trim_start_matches("sha256:")))
+}
+
+/// Unpack the image at `path` into `rootfs`, unless that was done meanwhile.
+fn unpack_image(reference: &str, path: &Path, rootfs: &Path) -> Result<(), ImageError> {
+    if rootfs.exists() {
+        return Ok(());
+    }
+    if !path.is_file() {
+        return unpack_layout(path, rootfs).map_err(|e| with_reference(e, reference));
+    }
+    let parent = rootfs.parent().unwrap_or(rootfs);
+    let layout = parent.join(format!("layout-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&layout)?;
+    let result = tar::Archive::new(BufReader::new(File::open(path)?))
+        .unpack(&layout)
+        .map_err(ImageError::from)
+        .and_then(|_| unpack_layout(&layout, rootfs).map_err(|e| with_reference(e, reference)));
+    let _ = std::fs::remove_dir_all(&layout);
+    result
+}
+
+/// Errors about the layout only learn which image they belong to here.
+fn with_reference(err: ImageError, reference: &str) -> ImageError {
+    match err {
+        ImageError::Invalid(_, msg) => ImageError::Invalid(reference.to_string(), msg),
+        err => err,
+    }
+}
+
+fn hex_digest(data: String) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data.as_bytes());
+    base16ct::lower::encode_string(&hasher.finalize())
+}
+
+fn invalid(msg: impl Into<String>) -> ImageError {
+    ImageError::Invalid(String::new(), msg.into())
+}
+
+/// The digest of the first manifest listed in the layout's index.
+fn manifest_digest(layout: &Path) -> Result<String, ImageError> {
+    let index: Index = serde_json::from_reader(File::open(layout.join("index.json"))?)
+        .map_err(|e| invalid(format!("index.json: {}", e)))?;
+    index
+        .manifests
+        .into_iter()
+        .next()
+        .map(|manifest| manifest.digest)
+        .ok_or_else(|| invalid("index.json lists no manifests"))
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf, ImageError> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| invalid(format!("malformed digest {}", digest)))?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid(format!("malformed digest {}", digest)));
+    }
+    Ok(layout.join("blobs").join(algorithm).join(hex))
+}
+
+/// Apply every layer of the layout's image on top of each other into `rootfs`.
+#[instrument]
+fn unpack_layout(layout: &Path, rootfs: &Path) -> Result<(), ImageError> {
+    let manifest = manifest_digest(layout)?;
+    let manifest: Manifest = serde_json::from_reader(File::open(blob_path(layout, &manifest)?)?)
+        .map_err(|e| invalid(format!("manifest: {}", e)))?;
+
+    // Built aside and renamed into place so a failed unpack leaves nothing behind
+    let parent = rootfs.parent().unwrap_or(rootfs);
+    let staging = parent.join(format!("unpacking-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&staging)?;
+    let result = manifest.layers.iter().try_for_each(|layer| {
+        info!("Applying layer {}", layer.digest);
+        let blob = BufReader::new(File::open(blob_path(layout, &layer.digest)?)?);
+        let gzipped = layer
+            .media_type
+            .as_deref()
+            .is_some_and(|media_type| media_type.ends_with("gzip"));
+        if gzipped {
+            apply_layer(flate2::read::GzDecoder::new(blob), &staging)
+        } else {
+            apply_layer(blob, &staging)
+        }
+    });
+    let result = result.and_then(|_| match std::fs::rename(&staging, rootfs) {
+        // Another image with the same manifest got there first
+        Err(_) if rootfs.is_dir() => Ok(()),
+        renamed => Ok(renamed?),
+    });
+    // Only left behind when it was not renamed into place
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+fn apply_layer<R: Read>(layer: R, rootfs: &Path) -> Result<(), ImageError> {
+    let mut archive = tar::Archive::new(layer);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let dir = resolve_in_root(path.parent().unwrap_or(Path::new("")))
+                .ok_or_else(|| invalid(format!("whiteout {} escapes the image", path.display())))?;
+            let dir = rootfs.join(dir);
+            // Removing through a symlink could reach outside of the image
+            check_parents(rootfs, &dir.join(hidden))?;
+            if name == OPAQUE_WHITEOUT {
+                if let Ok(children) = std::fs::read_dir(&dir) {
+                    for child in children {
+                        remove_path(&child?.path())?;
+                    }
+                }
+            } else {
+                remove_path(&dir.join(hidden))?;
+            }
+            continue;
+        }
+        // Device nodes cannot be created without privileges, the sandbox brings its own /dev
+        if matches!(
+            entry.header().entry_type(),
+            tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo
+        ) {
+            continue;
+        }
+        // A file replacing a directory of a lower layer, or the other way around
+        if let Some(existing) = resolve_in_root(&path).map(|path| rootfs.join(path)) {
+            if let Ok(meta) = std::fs::symlink_metadata(&existing) {
+                check_parents(rootfs, &existing)?;
+                if meta.is_dir() != entry.header().entry_type().is_dir() {
+                    remove_path(&existing)?;
+                }
+            }
+        }
+        entry.set_preserve_permissions(true);
+        entry.set_unpack_xattrs(false);
+        entry.unpack_in(rootfs)?;
+    }
+    Ok(())
+}
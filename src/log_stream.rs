@@ -0,0 +1,357 @@
+//! LogStreams: logs that can be read through the ByteStream API while they are still written.
+//!
+//! Every stream is backed by a file. Readers follow it until the stream is finalized, after which
+//! it stays readable for the retention window. Streams a client stopped writing to without
+//! finalizing them are finalized once idle for as long.
+
+use crate::api;
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tracing::info;
+use uuid::Uuid;
+
+/// How often readers look for new data of a stream nobody finalized yet.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Largest chunk handed to a reader at once.
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum LogStreamError {
+    #[error("I/O: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unknown log stream {0}")]
+    NotFound(String),
+
+    #[error("{0} is a write resource, read the log stream name instead")]
+    WriteResource(String),
+
+    #[error("Log stream {0} is already finalized")]
+    Finalized(String),
+
+    #[error("Write at offset {1} but the log stream is {0} bytes long")]
+    Offset(u64, i64),
+}
+
+impl From<LogStreamError> for tonic::Status {
+    fn from(err: LogStreamError) -> Self {
+        let code = match err {
+            LogStreamError::Io(_) => tonic::Code::Internal,
+            LogStreamError::NotFound(_) => tonic::Code::NotFound,
+            LogStreamError::WriteResource(_) | LogStreamError::Offset(..) => {
+                tonic::Code::InvalidArgument
+            }
+            LogStreamError::Finalized(_) => tonic::Code::FailedPrecondition,
+        };
+        tonic::Status::new(code, err.to_string())
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    write_resource_name: String,
+    /// Bytes written through the ByteStream API so far.
+    size: u64,
+    /// Held by a write from checking its offset until its data is in the file.
+    writing: Arc<tokio::sync::Mutex<()>>,
+    finalized: watch::Sender<bool>,
+    finalized_at: Option<Instant>,
+    /// Creation or the last write, whichever came later.
+    last_active: Instant,
+    /// Whether a [`LogWriter`] finalizes the stream, otherwise it is when left idle too long.
+    held: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// By stream name.
+    streams: HashMap<String, Entry>,
+    /// Stream name by write resource name.
+    writers: HashMap<String, String>,
+}
+
+impl Inner {
+    /// Forget streams finalized more than `retention` ago, along with their files. Streams
+    /// nobody wrote to for as long are finalized first, their writer is assumed gone.
+    fn prune(&mut self, retention: Duration) {
+        for (name, entry) in &mut self.streams {
+            if entry.finalized_at.is_none()
+                && !entry.held
+                && entry.last_active.elapsed() > retention
+            {
+                info!("Log stream {} left idle, finalizing it", name);
+                entry.finalized_at = Some(Instant::now());
+                entry.finalized.send_replace(true);
+            }
+        }
+        let writers = &mut self.writers;
+        self.streams.retain(|name, entry| match entry.finalized_at {
+            Some(finalized_at) if finalized_at.elapsed() > retention => {
+                info!("Log stream {} expired", name);
+                let _ = std::fs::remove_file(&entry.path);
+                writers.remove(&entry.write_resource_name);
+                false
+            }
+            _ => true,
+        });
+    }
+}
+
+/// Every log stream the server knows about.
+#[derive(Clone)]
+pub struct LogStreams {
+    inner: Arc<Mutex<Inner>>,
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl LogStreams {
+    pub fn new(dir: PathBuf, retention: Duration) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(LogStreams {
+            inner: Arc::new(Mutex::new(Inner::default())),
+            dir,
+            retention,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(self.retention);
+        inner
+    }
+
+    /// Create an empty stream below `parent`, written through its `write_resource_name`.
+    pub fn create(&self, parent: &str) -> Result<api::LogStream, LogStreamError> {
+        Ok(self.insert(parent, false)?.0)
+    }
+
+    fn insert(
+        &self,
+        parent: &str,
+        held: bool,
+    ) -> Result<(api::LogStream, PathBuf), LogStreamError> {
+        let id = Uuid::new_v4().to_string();
+        let path = self.dir.join(&id);
+        std::fs::File::create(&path)?;
+        let name = if parent.is_empty() {
+            format!("logstreams/{}", id)
+        } else {
+            format!("{}/logstreams/{}", parent.trim_end_matches('/'), id)
+        };
+        let write_resource_name = format!("{}/{}", name, Uuid::new_v4());
+        let (finalized, _) = watch::channel(false);
+        let mut inner = self.lock();
+        inner
+            .writers
+            .insert(write_resource_name.clone(), name.clone());
+        inner.streams.insert(
+            name.clone(),
+            Entry {
+                path: path.clone(),
+                write_resource_name: write_resource_name.clone(),
+                size: 0,
+                writing: Arc::default(),
+                finalized,
+                finalized_at: None,
+                last_active: Instant::now(),
+                held,
+            },
+        );
+        info!("Created log stream {}", name);
+        let stream = api::LogStream {
+            name,
+            write_resource_name,
+        };
+        Ok((stream, path))
+    }
+
+    /// A stream written by the server itself straight to its file, finalized once dropped.
+    pub fn open(&self, parent: &str) -> Result<LogWriter, LogStreamError> {
+        let (stream, path) = self.insert(parent, true)?;
+        Ok(LogWriter {
+            streams: self.clone(),
+            name: stream.name,
+            write_resource_name: stream.write_resource_name,
+            path,
+        })
+    }
+
+    pub fn is_write_resource(&self, resource_name: &str) -> bool {
+        self.lock().writers.contains_key(resource_name)
+    }
+
+    /// Mark a stream complete, readers stop once they caught up with it.
+    pub fn finalize(&self, name: &str) {
+        let mut inner = self.lock();
+        if let Some(entry) = inner.streams.get_mut(name) {
+            if entry.finalized_at.is_none() {
+                info!("Finalized log stream {}", name);
+                entry.finalized_at = Some(Instant::now());
+                entry.finalized.send_replace(true);
+            }
+        }
+    }
+
+    /// Append to a stream through its write resource, `offset` must be where the last write
+    /// ended. Returns the committed size.
+    pub async fn write(
+        &self,
+        write_resource_name: &str,
+        offset: i64,
+        data: &[u8],
+        finish: bool,
+    ) -> Result<u64, LogStreamError> {
+        let not_found = || LogStreamError::NotFound(write_resource_name.to_string());
+        let (name, writing) = {
+            let inner = self.lock();
+            let name = inner
+                .writers
+                .get(write_resource_name)
+                .ok_or_else(not_found)?;
+            (name.clone(), inner.streams[name].writing.clone())
+        };
+        // Concurrent writers to the same stream would otherwise both pass the offset check
+        let _writing = writing.lock().await;
+        let (path, size) = {
+            let inner = self.lock();
+            let entry = inner.streams.get(&name).ok_or_else(not_found)?;
+            if entry.finalized_at.is_some() {
+                return Err(LogStreamError::Finalized(name.clone()));
+            }
+            if offset != entry.size as i64 {
+                return Err(LogStreamError::Offset(entry.size, offset));
+            }
+            (entry.path.clone(), entry.size)
+        };
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(data).await?;
+        let size = size + data.len() as u64;
+        if let Some(entry) = self.lock().streams.get_mut(&name) {
+            entry.size = size;
+            entry.last_active = Instant::now();
+        }
+        if finish {
+            self.finalize(&name);
+        }
+        Ok(size)
+    }
+
+    /// Follow a stream from `offset`, `None` if `name` is not a log stream at all.
+    pub fn reader(&self, name: &str, offset: u64) -> Result<Option<LogReader>, LogStreamError> {
+        let inner = self.lock();
+        if inner.writers.contains_key(name) {
+            return Err(LogStreamError::WriteResource(name.to_string()));
+        }
+        let Some(entry) = inner.streams.get(name) else {
+            return Ok(None);
+        };
+        let mut file = std::fs::File::open(&entry.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Some(LogReader {
+            file: tokio::fs::File::from_std(file),
+            finalized: entry.finalized.subscribe(),
+        }))
+    }
+}
+
+/// The writing end of a stream whose file is written directly, e.g. by a sandboxed action.
+///
+/// A stream filled by a worker through its write resource can be held the same way, so it is
+/// finalized even when the worker never finishes it.
+pub struct LogWriter {
+    streams: LogStreams,
+    name: String,
+    write_resource_name: String,
+    path: PathBuf,
+}
+
+impl LogWriter {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn write_resource_name(&self) -> &str {
+        &self.write_resource_name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.streams.finalize(&self.name);
+    }
+}
+
+/// Reads a stream as it grows.
+pub struct LogReader {
+    file: tokio::fs::File,
+    finalized: watch::Receiver<bool>,
+}
+
+impl LogReader {
+    /// The next chunk of the stream, waiting for the writer when caught up with it.
+    ///
+    /// Returns `None` once everything of a finalized stream was read.
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, LogStreamError> {
+        loop {
+            // Checked before reading so the last write before finalizing is never missed
+            let finalized = *self.finalized.borrow();
+            let mut buf = vec![0; READ_CHUNK];
+            let read = self.file.read(&mut buf).await?;
+            if read > 0 {
+                buf.truncate(read);
+                return Ok(Some(buf));
+            }
+            if finalized {
+                return Ok(None);
+            }
+            tokio::select! {
+                _ = self.finalized.changed() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETENTION: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn idle_streams_are_finalized_then_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let streams = LogStreams::new(dir.path().to_path_buf(), RETENTION).unwrap();
+        let abandoned = streams.create("").unwrap();
+        let held = streams.open("").unwrap();
+
+        std::thread::sleep(RETENTION * 2);
+        let reader = streams.reader(&abandoned.name, 0).unwrap().unwrap();
+        assert!(*reader.finalized.borrow());
+        assert!(!*streams
+            .reader(held.name(), 0)
+            .unwrap()
+            .unwrap()
+            .finalized
+            .borrow());
+
+        std::thread::sleep(RETENTION * 2);
+        assert!(streams.reader(&abandoned.name, 0).unwrap().is_none());
+        assert!(!streams.is_write_resource(&abandoned.write_resource_name));
+        assert!(streams.reader(held.name(), 0).unwrap().is_some());
+    }
+}
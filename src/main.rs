@@ -1,57 +1,258 @@
-use clap::Parser;
-use std::{net::SocketAddr, path::PathBuf};
+use clap::{Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 use tonic::transport::Server;
-use tracing::{info, instrument};
+use tracing::info;
 
 mod services;
 use crate::services::*;
 
 mod action;
 mod api;
+mod asset;
+mod asset_index;
 mod blob;
+mod config;
 mod content_storage;
+mod execution_queue;
 mod execution_runner;
+mod image;
+mod log_stream;
+mod operation_registry;
+mod platform;
 mod sandboxed_action;
+mod scheduler;
+mod util;
+mod verify;
+mod worker;
+use asset::AssetMirror;
+use asset_index::AssetIndex;
+use config::Config;
 use content_storage::ContentStorage;
+use execution_queue::ExecutionQueue;
 use execution_runner::ExecutionRunner;
+use log_stream::LogStreams;
+use operation_registry::OperationRegistry;
+use scheduler::Scheduler;
+use verify::Verifier;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Address to listen on for remote execution requests.
-    #[arg(short, long)]
-    addr: SocketAddr,
+    #[arg(short, long, required = true)]
+    addr: Option<SocketAddr>,
 
     /// Storage directory.
+    #[arg(short, long, required = true)]
+    dir: Option<PathBuf>,
+
+    /// Server configuration file, see `config.rs` for the format.
     #[arg(short, long)]
-    dir: PathBuf,
+    config: Option<PathBuf>,
+
+    /// Seconds a finished operation can still be fetched or reattached to.
+    #[arg(long, default_value_t = 600)]
+    operation_retention: u64,
+
+    /// Seconds an operation keeps running after its last client stream disconnects.
+    #[arg(long, default_value_t = 30)]
+    orphan_grace: u64,
+
+    /// Actions run at once. Defaults to what the host's CPUs and memory allow, or no limit
+    /// with `--scheduler`.
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// MiB of memory to set aside for each worker when sizing the default worker count.
+    #[arg(long, default_value_t = 2048)]
+    memory_per_worker: u64,
+
+    /// Actions allowed to wait for a worker before new ones are turned away.
+    #[arg(long, default_value_t = 1024)]
+    max_queued: usize,
+
+    /// Most urgent execution priority accepted, lower values run first.
+    #[arg(long, default_value_t = -100, allow_negative_numbers = true)]
+    min_priority: i32,
+
+    /// Least urgent execution priority accepted.
+    #[arg(long, default_value_t = 100, allow_negative_numbers = true)]
+    max_priority: i32,
+
+    /// Milliseconds a queued action waits to gain one step of priority.
+    #[arg(long, default_value_t = 1000)]
+    priority_aging: u64,
+
+    /// Seconds an action may run when it does not specify a timeout.
+    #[arg(long, default_value_t = 900)]
+    default_action_timeout: u64,
+
+    /// Longest timeout in seconds an action may run for, longer requested timeouts are lowered
+    /// to it.
+    #[arg(long, default_value_t = 3600)]
+    max_action_timeout: u64,
+
+    /// Accept input symlinks pointing at absolute paths, resolved inside the sandbox.
+    #[arg(long)]
+    allow_absolute_symlinks: bool,
+
+    /// Directory holding the sandbox of every running action, one subdirectory each.
+    #[arg(long, default_value = "/tmp/gaudi/sandbox")]
+    sandbox_root: PathBuf,
+
+    /// Leave actions to `gaudi worker` processes leasing them through the Remote Workers API
+    /// instead of running them on this host.
+    #[arg(long)]
+    scheduler: bool,
+
+    /// Seconds a worker may go without calling in before its actions are handed to others. An
+    /// action no connected worker supports fails after waiting as long.
+    #[arg(long, default_value_t = 60)]
+    worker_timeout: u64,
+
+    /// Share of actions, from 0 to 1, run twice to check their outputs are deterministic.
+    /// Actions can also ask for it through the `verify-determinism` platform property. Their
+    /// result waits for both runs, so verified actions take about twice as long.
+    #[arg(long, default_value_t = 0.0)]
+    verify_sample_rate: f64,
+
+    /// Where nondeterministic actions are logged. Defaults to `nondeterminism.log` in the
+    /// storage directory.
+    #[arg(long)]
+    verify_log: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run actions leased from a scheduler instead of serving clients.
+    Worker(worker::WorkerArgs),
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let addr = args.addr;
-    let cas_dir = args.dir;
-    let sandbox_dir = PathBuf::from("/home/ben/workspace/gaudi/sandbox");
+    if let Some(Command::Worker(worker)) = args.command {
+        return worker::run(worker).await;
+    }
+    let (Some(addr), Some(cas_dir)) = (args.addr, args.dir) else {
+        unreachable!("clap requires --addr and --dir without a subcommand");
+    };
+    // Mounts are set up from inside the sandbox, so its paths must be absolute
+    std::fs::create_dir_all(&args.sandbox_root)?;
+    let sandbox_dir = std::fs::canonicalize(&args.sandbox_root)?;
 
     // We rely heavily on openat2
     assert!(openat2::has_openat2());
 
     info!("Initialized.");
 
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let sandbox = SandboxConfig::new(
+        &config,
+        args.allow_absolute_symlinks,
+        cas_dir.join("images"),
+    )?;
+    let asset_mirror = AssetMirror::new(config.fetch.as_ref());
+
+    // Action logs stay readable as long as their operation
+    let log_streams = LogStreams::new(
+        cas_dir.join("logstreams"),
+        Duration::from_secs(args.operation_retention),
+    )?;
+
+    // Pushed assets are remembered across restarts
+    let asset_index = AssetIndex::new(cas_dir.join("assets"))?;
+
+    let verifier = Verifier::new(
+        args.verify_sample_rate,
+        args.verify_log
+            .unwrap_or_else(|| cas_dir.join("nondeterminism.log")),
+    );
+
     // generic remote build structures
     let content_storage = ContentStorage::new(cas_dir)?;
-    let execution_runner = ExecutionRunner::new();
+    let operations = OperationRegistry::new(
+        Duration::from_secs(args.operation_retention),
+        Duration::from_secs(args.orphan_grace),
+    );
+    // Workers bring their own limits, the scheduler only holds actions back when told to
+    let workers = match args.workers {
+        Some(workers) => workers,
+        None if args.scheduler => usize::MAX,
+        None => execution_queue::host_slots(args.memory_per_worker * 1024 * 1024),
+    };
+    if args.min_priority > args.max_priority {
+        return Err("--min-priority must not be greater than --max-priority".into());
+    }
+    if !(0.0..=1.0).contains(&args.verify_sample_rate) {
+        return Err("--verify-sample-rate must be between 0 and 1".into());
+    }
+    let execution_queue = ExecutionQueue::new(
+        workers,
+        args.max_queued,
+        args.min_priority..=args.max_priority,
+        Duration::from_millis(args.priority_aging),
+    );
+    let execution_priorities = execution_queue.priorities().clone();
+    let execution_runner = ExecutionRunner::new(operations.clone(), execution_queue);
     //    execution_runner.spawn();
 
     // gRPC RBE services
-    let exec = ExecutionService::new(content_storage.clone(), sandbox_dir, execution_runner);
+    let timeouts = ActionTimeouts {
+        default: Duration::from_secs(args.default_action_timeout),
+        max: Duration::from_secs(args.max_action_timeout),
+    };
+    let caps = CapabilitiesService::new(
+        execution_priorities,
+        args.allow_absolute_symlinks,
+        &sandbox.platform,
+    );
+    let scheduler = Scheduler::new(
+        Duration::from_secs(args.worker_timeout),
+        Duration::from_millis(args.priority_aging),
+    );
+    if args.scheduler {
+        scheduler.spawn_sweeper();
+    }
+    let platform = sandbox.platform.clone();
+    let executor = if args.scheduler {
+        Executor::Remote(scheduler.clone())
+    } else {
+        Executor::Local {
+            sandbox_root: sandbox_dir,
+            sandbox,
+        }
+    };
+    let exec = ExecutionService::new(
+        content_storage.clone(),
+        log_streams.clone(),
+        execution_runner,
+        timeouts,
+        executor,
+        verifier,
+        platform,
+    );
+    let bots = args
+        .scheduler
+        .then(|| api::BotsServer::new(BotsService::new(scheduler.clone())));
+    let worker_status = args
+        .scheduler
+        .then(|| api::WorkerStatusServer::new(WorkerStatusService::new(scheduler)));
     let cas = ContentStorageService::default();
-    let caps = CapabilitiesService::default();
-    let ops = OperationsService::new();
+    let ops = OperationsService::new(operations);
     let action_cache = ActionCacheService::default();
-    let byte_stream = BytestreamService::new(content_storage.clone());
+    let byte_stream = BytestreamService::new(content_storage.clone(), log_streams.clone());
+    let log_stream = LogStreamService::new(log_streams);
+    let fetch = FetchService::new(content_storage.clone(), asset_mirror, asset_index.clone());
+    let push = PushService::new(content_storage.clone(), asset_index);
 
     info!("Serving on {}", addr);
     Server::builder()
@@ -61,6 +262,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .add_service(api::ByteStreamServer::new(byte_stream))
         .add_service(api::CapabilitiesServer::new(caps))
         .add_service(api::OperationsServer::new(ops))
+        .add_service(api::LogStreamServiceServer::new(log_stream))
+        .add_service(api::FetchServer::new(fetch))
+        .add_service(api::PushServer::new(push))
+        .add_optional_service(bots)
+        .add_optional_service(worker_status)
         .serve(addr)
         .await?;
 
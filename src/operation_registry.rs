@@ -0,0 +1,457 @@
+use crate::api;
+use futures::Future;
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{watch, Notify};
+use tracing::info;
+use uuid::Uuid;
+
+/// Number of operations returned by a listing when the client does not ask for a page size.
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum OperationError {
+    #[error("Unsupported filter: {0}")]
+    InvalidFilter(String),
+
+    #[error("Invalid page token: {0}")]
+    InvalidPageToken(String),
+}
+
+/// Latest known state of an execution.
+#[derive(Clone, Debug)]
+pub struct OperationState {
+    pub name: String,
+    pub metadata: api::ExecuteOperationMetadata,
+    pub response: Option<api::ExecuteResponse>,
+}
+
+impl OperationState {
+    pub fn done(&self) -> bool {
+        self.response.is_some()
+    }
+
+    pub fn stage(&self) -> api::execution_stage::Value {
+        api::execution_stage::Value::from_i32(self.metadata.stage)
+            .unwrap_or(api::execution_stage::Value::Unknown)
+    }
+
+    /// Pack the state into the longrunning representation handed to clients.
+    pub fn to_operation(&self) -> api::Operation {
+        api::Operation {
+            name: self.name.clone(),
+            done: self.done(),
+            metadata: Some(prost_types::Any {
+                type_url: String::from(
+                    "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata",
+                ),
+                value: self.metadata.encode_to_vec(),
+            }),
+            result: self.response.as_ref().map(|response| {
+                api::operation::Result::Response(prost_types::Any {
+                    type_url: String::from(
+                        "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteResponse",
+                    ),
+                    value: response.encode_to_vec(),
+                })
+            }),
+        }
+    }
+}
+
+/// Subset of the longrunning filter syntax we understand, e.g. `done=false AND stage=EXECUTING`.
+#[derive(Debug, Default)]
+struct Filter {
+    done: Option<bool>,
+    stage: Option<api::execution_stage::Value>,
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Self, OperationError> {
+        let mut parsed = Filter::default();
+        if filter.trim().is_empty() {
+            return Ok(parsed);
+        }
+        for term in filter.split(" AND ") {
+            let (key, value) = term
+                .split_once('=')
+                .ok_or_else(|| OperationError::InvalidFilter(term.to_string()))?;
+            match (key.trim(), value.trim()) {
+                ("done", "true") => parsed.done = Some(true),
+                ("done", "false") => parsed.done = Some(false),
+                ("stage", stage) => {
+                    parsed.stage = Some(
+                        api::execution_stage::Value::from_str_name(stage)
+                            .ok_or_else(|| OperationError::InvalidFilter(term.to_string()))?,
+                    )
+                }
+                _ => return Err(OperationError::InvalidFilter(term.to_string())),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, state: &OperationState) -> bool {
+        self.done.is_none_or(|done| state.done() == done)
+            && self.stage.is_none_or(|stage| state.stage() == stage)
+    }
+}
+
+struct Entry {
+    /// Creation order, used to give listings a stable order and as the page token.
+    seq: u64,
+    state: watch::Sender<OperationState>,
+    completed_at: Option<Instant>,
+    cancel: Arc<Notify>,
+    /// Client streams currently attached to the operation.
+    watchers: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_seq: u64,
+    operations: HashMap<String, Entry>,
+}
+
+impl Inner {
+    /// Forget operations that finished more than `retention` ago.
+    fn prune(&mut self, retention: Duration) {
+        self.operations
+            .retain(|name, entry| match entry.completed_at {
+                Some(completed_at) if completed_at.elapsed() > retention => {
+                    info!("Operation {} expired", name);
+                    false
+                }
+                _ => true,
+            });
+    }
+}
+
+/// A client stream attached to an operation.
+///
+/// Once the last watcher of an unfinished operation goes away and nobody reattaches within the
+/// grace period, the operation is cancelled.
+pub struct Watcher {
+    registry: OperationRegistry,
+    name: String,
+    pub updates: watch::Receiver<OperationState>,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.registry.detach(&self.name);
+    }
+}
+
+/// Shared record of every in-flight and recently finished execution.
+///
+/// Completed operations stay reattachable for the retention window.
+#[derive(Clone)]
+pub struct OperationRegistry {
+    inner: Arc<Mutex<Inner>>,
+    retention: Duration,
+    orphan_grace: Duration,
+}
+
+impl OperationRegistry {
+    pub fn new(retention: Duration, orphan_grace: Duration) -> Self {
+        OperationRegistry {
+            inner: Arc::new(Mutex::new(Inner::default())),
+            retention,
+            orphan_grace,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(self.retention);
+        inner
+    }
+
+    /// Register a new queued operation for the action and return its name.
+    pub fn create(&self, action_digest: api::Digest) -> String {
+        let name = Uuid::new_v4().to_string();
+        let state = OperationState {
+            name: name.clone(),
+            metadata: api::ExecuteOperationMetadata {
+                stage: api::execution_stage::Value::Queued.into(),
+                action_digest: Some(action_digest),
+                stdout_stream_name: String::new(),
+                stderr_stream_name: String::new(),
+            },
+            response: None,
+        };
+        let mut inner = self.lock();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        let (state, _) = watch::channel(state);
+        inner.operations.insert(
+            name.clone(),
+            Entry {
+                seq,
+                state,
+                completed_at: None,
+                cancel: Arc::new(Notify::new()),
+                watchers: 0,
+            },
+        );
+        info!("Created operation {}", name);
+        name
+    }
+
+    /// Tell clients where the action's output can be followed while it runs.
+    pub fn set_log_streams(&self, name: &str, stdout: &str, stderr: &str) {
+        self.update(name, |state| {
+            state.metadata.stdout_stream_name = stdout.to_string();
+            state.metadata.stderr_stream_name = stderr.to_string();
+        });
+    }
+
+    pub fn set_stage(&self, name: &str, stage: api::execution_stage::Value) {
+        self.update(name, |state| state.metadata.stage = stage.into());
+    }
+
+    /// Record the final response of an operation and wake anyone waiting on it.
+    pub fn complete(&self, name: &str, response: api::ExecuteResponse) {
+        self.update(name, |state| {
+            state.metadata.stage = api::execution_stage::Value::Completed.into();
+            state.response = Some(response);
+        });
+    }
+
+    fn update<F: FnOnce(&mut OperationState)>(&self, name: &str, f: F) {
+        let mut inner = self.lock();
+        match inner.operations.get_mut(name) {
+            Some(entry) => {
+                entry.state.send_modify(f);
+                if entry.state.borrow().done() && entry.completed_at.is_none() {
+                    entry.completed_at = Some(Instant::now());
+                }
+            }
+            None => info!("Operation {} is no longer tracked", name),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<OperationState> {
+        let inner = self.lock();
+        inner
+            .operations
+            .get(name)
+            .map(|entry| entry.state.borrow().clone())
+    }
+
+    /// Watch an operation for stage changes. The receiver ends when the operation is deleted.
+    pub fn subscribe(&self, name: &str) -> Option<watch::Receiver<OperationState>> {
+        let inner = self.lock();
+        inner
+            .operations
+            .get(name)
+            .map(|entry| entry.state.subscribe())
+    }
+
+    /// Attach a client stream to an operation, see [`Watcher`].
+    pub fn attach(&self, name: &str) -> Option<Watcher> {
+        let mut inner = self.lock();
+        let entry = inner.operations.get_mut(name)?;
+        entry.watchers += 1;
+        Some(Watcher {
+            registry: self.clone(),
+            name: name.to_string(),
+            updates: entry.state.subscribe(),
+        })
+    }
+
+    fn detach(&self, name: &str) {
+        let mut inner = self.lock();
+        let Some(entry) = inner.operations.get_mut(name) else {
+            return;
+        };
+        entry.watchers -= 1;
+        if entry.watchers > 0 || entry.state.borrow().done() {
+            return;
+        }
+        info!("Last watcher of {} went away", name);
+        let registry = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(registry.orphan_grace).await;
+            let orphaned = registry
+                .lock()
+                .operations
+                .get(&name)
+                .is_some_and(|entry| entry.watchers == 0);
+            if orphaned {
+                info!("Nobody reattached to {}", name);
+                registry.cancel(&name);
+            }
+        });
+    }
+
+    /// Resolves once cancellation of the operation has been requested.
+    pub fn cancelled(&self, name: &str) -> impl Future<Output = ()> {
+        let cancel = self
+            .lock()
+            .operations
+            .get(name)
+            .map(|entry| entry.cancel.clone());
+        async move {
+            match cancel {
+                Some(cancel) => cancel.notified().await,
+                None => futures::future::pending().await,
+            }
+        }
+    }
+
+    /// Ask the runner to stop an operation. Finished operations are left untouched.
+    ///
+    /// Returns false if the operation is not known.
+    pub fn cancel(&self, name: &str) -> bool {
+        let inner = self.lock();
+        let Some(entry) = inner.operations.get(name) else {
+            return false;
+        };
+        if !entry.state.borrow().done() {
+            info!("Cancelling {}", name);
+            entry.cancel.notify_one();
+        }
+        true
+    }
+
+    /// Stop tracking an operation. Returns false if it was not known.
+    pub fn delete(&self, name: &str) -> bool {
+        let mut inner = self.lock();
+        inner.operations.remove(name).is_some()
+    }
+
+    /// List operations matching `filter` in creation order, starting at `page_token`.
+    ///
+    /// Returns the page and the token for the next one, empty when there are no more.
+    pub fn list(
+        &self,
+        filter: &str,
+        page_size: i32,
+        page_token: &str,
+    ) -> Result<(Vec<OperationState>, String), OperationError> {
+        let filter = Filter::parse(filter)?;
+        let start = if page_token.is_empty() {
+            0
+        } else {
+            page_token
+                .parse::<u64>()
+                .map_err(|_| OperationError::InvalidPageToken(page_token.to_string()))?
+        };
+        let page_size = if page_size > 0 {
+            page_size as usize
+        } else {
+            DEFAULT_PAGE_SIZE
+        };
+
+        let inner = self.lock();
+        let mut matching: Vec<(u64, OperationState)> = inner
+            .operations
+            .values()
+            .filter(|entry| entry.seq >= start)
+            .map(|entry| (entry.seq, entry.state.borrow().clone()))
+            .filter(|(_, state)| filter.matches(state))
+            .collect();
+        matching.sort_by_key(|(seq, _)| *seq);
+
+        let next_page_token = matching
+            .get(page_size)
+            .map(|(seq, _)| seq.to_string())
+            .unwrap_or_default();
+        matching.truncate(page_size);
+        Ok((
+            matching.into_iter().map(|(_, state)| state).collect(),
+            next_page_token,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::execution_stage::Value as Stage;
+
+    fn registry() -> OperationRegistry {
+        OperationRegistry::new(Duration::from_secs(60), Duration::from_secs(60))
+    }
+
+    fn names(page: &[OperationState]) -> Vec<&str> {
+        page.iter().map(|state| state.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_filters() {
+        let filter = Filter::parse("").unwrap();
+        assert_eq!((filter.done, filter.stage), (None, None));
+
+        let filter = Filter::parse("done=false AND stage=EXECUTING").unwrap();
+        assert_eq!(filter.done, Some(false));
+        assert_eq!(filter.stage, Some(Stage::Executing));
+
+        let filter = Filter::parse(" done = true ").unwrap();
+        assert_eq!(filter.done, Some(true));
+    }
+
+    #[test]
+    fn reject_unknown_filters() {
+        for filter in [
+            "done",
+            "done=maybe",
+            "stage=RUNNING",
+            "name=foo",
+            "done=true OR done=false",
+        ] {
+            assert!(
+                matches!(Filter::parse(filter), Err(OperationError::InvalidFilter(_))),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn list_pages_in_creation_order() {
+        let registry = registry();
+        let created: Vec<String> = (0..5)
+            .map(|_| registry.create(api::Digest::default()))
+            .collect();
+
+        let (page, token) = registry.list("", 2, "").unwrap();
+        assert_eq!(names(&page), [&created[0], &created[1]]);
+        let (page, token) = registry.list("", 2, &token).unwrap();
+        assert_eq!(names(&page), [&created[2], &created[3]]);
+        let (page, token) = registry.list("", 2, &token).unwrap();
+        assert_eq!(names(&page), [&created[4]]);
+        assert!(token.is_empty());
+    }
+
+    #[test]
+    fn list_filters_operations() {
+        let registry = registry();
+        let queued = registry.create(api::Digest::default());
+        let executing = registry.create(api::Digest::default());
+        let completed = registry.create(api::Digest::default());
+        registry.set_stage(&executing, Stage::Executing);
+        registry.complete(&completed, api::ExecuteResponse::default());
+
+        let (page, _) = registry.list("done=false", 0, "").unwrap();
+        assert_eq!(names(&page), [&queued, &executing]);
+        let (page, _) = registry.list("stage=EXECUTING", 0, "").unwrap();
+        assert_eq!(names(&page), [&executing]);
+        let (page, _) = registry.list("done=true", 0, "").unwrap();
+        assert_eq!(names(&page), [&completed]);
+    }
+
+    #[test]
+    fn reject_invalid_page_tokens() {
+        assert!(matches!(
+            registry().list("", 0, "next"),
+            Err(OperationError::InvalidPageToken(_))
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+//! Platform properties requested by actions, checked against what the server supports.
+
+use crate::api;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// Property that gives the action access to the host network when set to `on`.
+pub const NETWORK: &str = "network";
+/// Property naming the toolchain whose mounts are added to the sandbox.
+pub const TOOLCHAIN: &str = "toolchain";
+/// Property naming the container image whose filesystem the action runs on.
+pub const CONTAINER_IMAGE: &str = "container-image";
+/// Property that has the action run twice and its outputs compared when set to `on`, see
+/// `verify.rs`. Like any property it has to be configured before clients can ask for it.
+pub const VERIFY: &str = "verify-determinism";
+
+#[derive(Error, Debug)]
+pub enum PlatformError {
+    #[error("Unsupported platform property {0}")]
+    UnknownProperty(String),
+
+    #[error("Platform property {0} does not support {1:?}, expected one of {2:?}")]
+    UnsupportedValue(String, String, Vec<String>),
+
+    #[error("Platform property {0} is set to both {1:?} and {2:?}")]
+    Conflict(String, String, String),
+}
+
+/// A platform property the server accepts.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PropertyConfig {
+    /// Accepted values, any value is accepted when empty.
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// Used when the action does not set the property.
+    pub default: Option<String>,
+}
+
+/// The platform properties an action runs with, one value per name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Platform(BTreeMap<String, String>);
+
+impl Platform {
+    /// Merge the properties of an action and its command, the action's win as in REv2.2.
+    pub fn requested(
+        action: Option<&api::Platform>,
+        command: Option<&api::Platform>,
+    ) -> Result<Self, PlatformError> {
+        let mut requested = flatten(command)?;
+        requested.extend(flatten(action)?);
+        Ok(Platform(requested))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let properties: Vec<String> = self
+            .0
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        write!(f, "{{{}}}", properties.join(", "))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PlatformConfig {
+    properties: BTreeMap<String, PropertyConfig>,
+}
+
+impl PlatformConfig {
+    pub fn new(properties: BTreeMap<String, PropertyConfig>) -> Self {
+        PlatformConfig { properties }
+    }
+
+    /// Names of all supported properties.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.properties.keys()
+    }
+
+    /// Every supported property with its accepted values, none meaning any value.
+    pub fn properties(&self) -> impl Iterator<Item = (&String, &[String])> {
+        self.properties
+            .iter()
+            .map(|(name, config)| (name, &config.values[..]))
+    }
+
+    /// Merge the properties of an action and its command, the action's win as in REv2.2, and
+    /// check the result can be satisfied. Unset properties take their configured default.
+    pub fn resolve(
+        &self,
+        action: Option<&api::Platform>,
+        command: Option<&api::Platform>,
+    ) -> Result<Platform, PlatformError> {
+        let requested = Platform::requested(action, command)?;
+        self.check(&requested)?;
+        Ok(self.with_defaults(requested))
+    }
+
+    /// The configured default of every property `requested` leaves unset added to it.
+    pub fn with_defaults(&self, requested: Platform) -> Platform {
+        let Platform(mut resolved) = requested;
+        for (name, config) in &self.properties {
+            if let Some(default) = &config.default {
+                resolved
+                    .entry(name.clone())
+                    .or_insert_with(|| default.clone());
+            }
+        }
+        Platform(resolved)
+    }
+
+    /// Check every requested property is supported with the requested value.
+    pub fn check(&self, requested: &Platform) -> Result<(), PlatformError> {
+        for (name, value) in &requested.0 {
+            let config = self
+                .properties
+                .get(name)
+                .ok_or_else(|| PlatformError::UnknownProperty(name.clone()))?;
+            if !config.values.is_empty() && !config.values.contains(value) {
+                return Err(PlatformError::UnsupportedValue(
+                    name.clone(),
+                    value.clone(),
+                    config.values.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One value per property name, repeating a name is only allowed with the same value.
+fn flatten(platform: Option<&api::Platform>) -> Result<BTreeMap<String, String>, PlatformError> {
+    let mut properties = BTreeMap::new();
+    for property in platform.iter().flat_map(|platform| &platform.properties) {
+        if let Some(previous) = properties.insert(property.name.clone(), property.value.clone()) {
+            if previous != property.value {
+                return Err(PlatformError::Conflict(
+                    property.name.clone(),
+                    previous,
+                    property.value.clone(),
+                ));
+            }
+        }
+    }
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform(properties: &[(&str, &str)]) -> api::Platform {
+        api::Platform {
+            properties: properties
+                .iter()
+                .map(|(name, value)| api::platform::Property {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn config() -> PlatformConfig {
+        PlatformConfig::new(BTreeMap::from([
+            (
+                String::from(NETWORK),
+                PropertyConfig {
+                    values: vec![String::from("off"), String::from("on")],
+                    default: Some(String::from("off")),
+                },
+            ),
+            (String::from(TOOLCHAIN), PropertyConfig::default()),
+        ]))
+    }
+
+    fn resolved(properties: &[(&str, &str)]) -> Platform {
+        Platform(
+            properties
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn resolve_applies_defaults() {
+        let defaults = config().resolve(None, None).unwrap();
+        assert_eq!(defaults, resolved(&[(NETWORK, "off")]));
+        let requested = platform(&[(NETWORK, "on")]);
+        let overridden = config().resolve(Some(&requested), None).unwrap();
+        assert_eq!(overridden, resolved(&[(NETWORK, "on")]));
+    }
+
+    #[test]
+    fn action_properties_win_over_the_command() {
+        let action = platform(&[(NETWORK, "on")]);
+        let command = platform(&[(NETWORK, "off"), (TOOLCHAIN, "gcc12")]);
+        let platform = config().resolve(Some(&action), Some(&command)).unwrap();
+        assert_eq!(platform, resolved(&[(NETWORK, "on"), (TOOLCHAIN, "gcc12")]));
+    }
+
+    #[test]
+    fn check_rejects_unsupported_properties() {
+        let config = config();
+        assert!(config.check(&resolved(&[(TOOLCHAIN, "anything")])).is_ok());
+        assert!(matches!(
+            config.check(&resolved(&[("arch", "arm64")])),
+            Err(PlatformError::UnknownProperty(name)) if name == "arch"
+        ));
+        assert!(matches!(
+            config.check(&resolved(&[(NETWORK, "maybe")])),
+            Err(PlatformError::UnsupportedValue(name, value, _)) if name == NETWORK && value == "maybe"
+        ));
+    }
+
+    #[test]
+    fn conflicting_properties() {
+        let repeated = platform(&[(NETWORK, "on"), (NETWORK, "on")]);
+        assert!(config().resolve(Some(&repeated), None).is_ok());
+        let conflicting = platform(&[(NETWORK, "on"), (NETWORK, "off")]);
+        assert!(matches!(
+            config().resolve(Some(&conflicting), None),
+            Err(PlatformError::Conflict(name, first, second))
+                if name == NETWORK && first == "on" && second == "off"
+        ));
+    }
+}
@@ -1,33 +1,108 @@
-use crate::action::platform::linux::{clone3, syscall_2};
+use crate::action::platform::linux::{clone3, syscall_2, syscall_4};
 use std::{
     ffi::CString,
-    io::{self, Write},
-    os::fd::{FromRawFd, IntoRawFd, RawFd},
-    path::{Path, PathBuf},
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Component, Path, PathBuf},
     ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Mutex, PoisonError},
 };
+use thiserror::Error;
 use tokio::io::unix::AsyncFd;
 use tracing::{info, instrument, span, Level};
 
+/// Tags for the report the sandbox process sends back when it fails before running the command.
+const SETUP_FAILED: u8 = b'S';
+const EXEC_FAILED: u8 = b'E';
+
+#[derive(Error, Debug)]
+pub enum SandboxError {
+    #[error("I/O: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid command: {0:?} contains a NUL byte")]
+    InvalidCommand(String),
+
+    #[error("Sandbox setup failed: {0}")]
+    Setup(String),
+
+    #[error("Could not execute {0}: {1}")]
+    Exec(String, String),
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Mapping {
     pub dest_path: PathBuf,
     pub source_path: PathBuf,
 }
 
+/// A symlink created in the sandbox, `path` as seen from outside of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symlink {
+    pub path: PathBuf,
+    pub target: PathBuf,
+}
+
+/// An output of the action.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Output {
+    /// As the client declared it, relative to the working directory.
+    pub name: String,
+    /// Where the action leaves it, seen from outside of the sandbox.
+    pub path: PathBuf,
+}
+
+/// The directory an action runs in, removed with everything inside once dropped.
+#[derive(Debug)]
+pub struct SandboxDir {
+    path: PathBuf,
+}
+
+impl SandboxDir {
+    /// A fresh directory below `root`, no other action ever sees it.
+    pub fn create(root: &Path) -> io::Result<Self> {
+        let path = root.join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&path)?;
+        Ok(SandboxDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SandboxDir {
+    fn drop(&mut self) {
+        // Mounts only exist in the sandbox's namespace, out here they are plain files and dirs
+        if let Err(e) = remove_path(&self.path) {
+            info!("Failed to remove sandbox {}: {}", self.path.display(), e);
+        }
+    }
+}
+
 pub struct AsyncSandboxedAction {
-    inner: AsyncFd<RawFd>,
-    output_files_map: Vec<Mapping>,
+    inner: AsyncFd<OwnedFd>,
+    output_files: Vec<Output>,
+    output_directories: Vec<Output>,
+    output_paths: Vec<Output>,
     stdout: PathBuf,
     stderr: PathBuf,
+    /// Set once the sandbox process has been reaped.
+    exited: AtomicBool,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct SandboxedActionResp {
     pub status_code: i32,
-    pub output_paths: Vec<Mapping>,
+    pub output_files: Vec<Output>,
+    pub output_directories: Vec<Output>,
+    /// Outputs whose type is only known once the action ran.
+    pub output_paths: Vec<Output>,
     pub stdout: PathBuf,
     pub stderr: PathBuf,
+    /// The action was killed for running past its deadline.
+    pub timed_out: bool,
 }
 
 impl AsyncSandboxedAction {
@@ -39,23 +114,35 @@ impl AsyncSandboxedAction {
                 info!("Got a pidfd update");
                 unsafe {
                     let mut infop: libc::siginfo_t = std::mem::zeroed();
-                    let id = (*inner.get_ref()) as u32;
+                    let id = inner.get_ref().as_raw_fd() as u32;
                     err_check(libc::waitid(libc::P_PIDFD, id, &mut infop, libc::WEXITED))?;
-                    assert_eq!(infop.si_signo, libc::SIGCHLD);
-                    match infop.si_code {
+                    self.exited.store(true, Ordering::SeqCst);
+                    let status_code = match infop.si_code {
                         libc::CLD_EXITED => {
-                            info!("exited with status: {}", infop.si_code);
-                            let stdout = self.stdout.clone();
-                            let stderr = self.stderr.clone();
-                            return Ok(SandboxedActionResp {
-                                status_code: infop.si_status(),
-                                output_paths: self.output_files_map.clone(),
-                                stdout,
-                                stderr,
-                            });
+                            info!("exited with status: {}", infop.si_status());
+                            infop.si_status()
+                        }
+                        libc::CLD_KILLED | libc::CLD_DUMPED => {
+                            info!("killed by signal: {}", infop.si_status());
+                            // Same convention as the shell
+                            128 + infop.si_status()
+                        }
+                        c => {
+                            return Err(io::Error::other(format!(
+                                "unexpected sandbox process state: {}",
+                                c
+                            )))
                         }
-                        c => panic!("new code: {}", c),
                     };
+                    Ok(SandboxedActionResp {
+                        status_code,
+                        output_files: self.output_files.clone(),
+                        output_directories: self.output_directories.clone(),
+                        output_paths: self.output_paths.clone(),
+                        stdout: self.stdout.clone(),
+                        stderr: self.stderr.clone(),
+                        timed_out: false,
+                    })
                 }
             }) {
                 Ok(result) => return result,
@@ -63,6 +150,45 @@ impl AsyncSandboxedAction {
             }
         }
     }
+
+    /// SIGKILL the sandbox through its pidfd.
+    ///
+    /// The sandboxed process is the init of its own PID namespace, so the kernel takes the whole
+    /// process tree down with it.
+    pub fn kill(&self) -> io::Result<()> {
+        let pid_fd = self.inner.get_ref().as_raw_fd();
+        let ret = unsafe {
+            syscall_4(
+                libc::SYS_pidfd_send_signal as usize,
+                pid_fd as usize,
+                libc::SIGKILL as usize,
+                0,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::from_raw_os_error(-ret));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AsyncSandboxedAction {
+    /// An action dropped before it exited was abandoned, kill it.
+    fn drop(&mut self) {
+        if self.exited.load(Ordering::SeqCst) {
+            return;
+        }
+        info!("Tearing down unfinished sandbox");
+        if let Err(e) = self.kill() {
+            info!("Failed to kill sandbox: {}", e);
+        }
+        unsafe {
+            let mut infop: libc::siginfo_t = std::mem::zeroed();
+            let id = self.inner.get_ref().as_raw_fd() as u32;
+            let _ = err_check(libc::waitid(libc::P_PIDFD, id, &mut infop, libc::WEXITED));
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -71,41 +197,44 @@ pub struct SandboxedAction {
     sandbox_location: PathBuf,
     arguments: Vec<String>,
     environment: Vec<(String, String)>,
-    output_files: Vec<PathBuf>,
     input_files: Vec<Mapping>,
-    output_files_map: Vec<Mapping>,
-    stdout: (PathBuf, RawFd),
-    stderr: (PathBuf, RawFd),
+    writable_mounts: Vec<Mapping>,
+    input_symlinks: Vec<Symlink>,
+    /// Top level links of the image, made before anything is mounted.
+    image_symlinks: Vec<Symlink>,
+    working_directory: PathBuf,
+    /// Share the host network instead of running without one.
+    network: bool,
+    output_files: Vec<Output>,
+    output_directories: Vec<Output>,
+    output_paths: Vec<Output>,
+    /// Files the action's output is appended to, discarded when unset.
+    stdout: PathBuf,
+    stderr: PathBuf,
 }
 
 impl SandboxedAction {
-    pub fn new(program: &str) -> Self {
-        let stderr_path = format!("/tmp/{}", uuid::Uuid::new_v4()).into();
-        let err_temp_file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&stderr_path)
-            .unwrap();
-        let stderr_fd = err_temp_file.into_raw_fd();
-        let stdout_path = format!("/tmp/{}", uuid::Uuid::new_v4()).into();
-        let std_temp_file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&stdout_path)
-            .unwrap();
-        let stdout_fd = std_temp_file.into_raw_fd();
-
+    /// Run `program` in `sandbox_location`, which holds nothing but what this action brings.
+    pub fn new(program: &str, sandbox_location: &Path) -> Self {
         SandboxedAction {
             program: program.into(),
-            sandbox_location: PathBuf::from("/home/ben/workspace/gaudi/sandbox"),
-            stdout: (stdout_path, stdout_fd),
-            stderr: (stderr_path, stderr_fd),
+            sandbox_location: sandbox_location.to_path_buf(),
             ..Default::default()
         }
     }
 
+    /// Append what the action writes to stdout to `path`, readable while the action runs.
+    pub fn stdout(mut self, path: &Path) -> Self {
+        self.stdout = path.to_path_buf();
+        self
+    }
+
+    /// Append what the action writes to stderr to `path`, readable while the action runs.
+    pub fn stderr(mut self, path: &Path) -> Self {
+        self.stderr = path.to_path_buf();
+        self
+    }
+
     pub fn args(mut self, args: &[String]) -> Self {
         self.arguments.extend_from_slice(args);
         self
@@ -116,22 +245,63 @@ impl SandboxedAction {
         self
     }
 
-    pub fn output_files(mut self, output_files: &[PathBuf]) -> Self {
-        self.output_files.extend_from_slice(output_files);
+    /// Directory relative to the input root the program runs in, outputs are relative to it.
+    pub fn working_directory(mut self, working_directory: &Path) -> Self {
+        self.working_directory = working_directory.to_path_buf();
+        self
+    }
+
+    pub fn network(mut self, network: bool) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Outputs are written straight into the sandbox, their parent directories exist up front.
+    fn prepare_output(&self, name: &str) -> io::Result<Output> {
+        let relative = resolve_in_root(&self.working_directory.join(name))
+            .filter(|path| !path.as_os_str().is_empty())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("output {} is outside of the input root", name),
+                )
+            })?;
+        let path = self.sandbox_location.join(relative);
+        check_parents(&self.sandbox_location, &path)?;
+        if let Some(prefix) = path.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+        Ok(Output {
+            name: name.to_string(),
+            path,
+        })
+    }
+
+    pub fn output_files(mut self, output_files: &[String]) -> io::Result<Self> {
         for output_file in output_files {
-            let temp_file = PathBuf::from(format!("/tmp/{}", uuid::Uuid::new_v4()));
-            std::fs::File::create(&temp_file).unwrap();
-            self.output_files_map.push(Mapping {
-                dest_path: PathBuf::from(format!(
-                    "{}/{}",
-                    self.sandbox_location.display(),
-                    output_file.display()
-                )),
-                source_path: temp_file,
-            });
+            let output = self.prepare_output(output_file)?;
+            self.output_files.push(output);
         }
-        info!("OUT MAP {:#?}", self.output_files_map);
-        self
+        Ok(self)
+    }
+
+    /// Output directories exist before the action starts.
+    pub fn output_directories(mut self, output_directories: &[String]) -> io::Result<Self> {
+        for output_directory in output_directories {
+            let output = self.prepare_output(output_directory)?;
+            std::fs::create_dir(&output.path)?;
+            self.output_directories.push(output);
+        }
+        Ok(self)
+    }
+
+    /// Outputs that may turn out to be a file, a directory or a symlink.
+    pub fn output_paths(mut self, output_paths: &[String]) -> io::Result<Self> {
+        for output_path in output_paths {
+            let output = self.prepare_output(output_path)?;
+            self.output_paths.push(output);
+        }
+        Ok(self)
     }
 
     pub fn input_file_mapping(mut self, mapping: &[Mapping]) -> Self {
@@ -139,117 +309,206 @@ impl SandboxedAction {
         self
     }
 
-    pub fn input_file(mut self, path: &str) -> Self {
-        let source_path = PathBuf::from(path);
-        self.input_files.push(Mapping {
-            dest_path: PathBuf::from(format!("{}/{}", self.sandbox_location.display(), path)),
-            source_path,
-        });
+    pub fn input_symlinks(mut self, symlinks: &[Symlink]) -> Self {
+        self.input_symlinks.extend_from_slice(symlinks);
         self
     }
 
-    #[instrument(skip_all)]
-    pub fn spawn(&mut self) -> io::Result<AsyncSandboxedAction> {
+    /// Use an unpacked image as the sandbox's filesystem. Its top level entries are bound
+    /// read-only, so this comes before any other mount that may land inside them.
+    pub fn root_filesystem(mut self, rootfs: &Path) -> io::Result<Self> {
+        for entry in std::fs::read_dir(rootfs)? {
+            let entry = entry?;
+            let name = PathBuf::from(entry.file_name());
+            // The sandbox sets up its own /dev and /proc, and /tmp stays writable
+            if ["dev", "proc", "tmp"]
+                .iter()
+                .any(|skip| name == Path::new(skip))
+            {
+                continue;
+            }
+            if entry.file_type()?.is_symlink() {
+                self.image_symlinks.push(Symlink {
+                    path: self.sandbox_location.join(&name),
+                    target: std::fs::read_link(entry.path())?,
+                });
+            } else {
+                self = self.mount(&entry.path(), &Path::new("/").join(&name), true);
+            }
+        }
+        std::fs::create_dir_all(self.sandbox_location.join("tmp"))?;
+        Ok(self)
+    }
+
+    /// Bind a host path into the sandbox at `target`.
+    pub fn mount(mut self, source: &Path, target: &Path, readonly: bool) -> Self {
+        let mapping = Mapping {
+            dest_path: PathBuf::from(format!(
+                "{}/{}",
+                self.sandbox_location.display(),
+                target.display()
+            )),
+            source_path: source.to_path_buf(),
+        };
+        if readonly {
+            self.input_files.push(mapping);
+        } else {
+            self.writable_mounts.push(mapping);
+        }
+        self
+    }
+
+    /// Everything the sandbox process does between clone and exec.
+    fn enter_sandbox(&self, uid: u32, gid: u32, stdout: RawFd, stderr: RawFd) -> io::Result<()> {
+        unsafe {
+            // Kill with SIGKILL if Parent dies
+            err_check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+        }
+        info!("Setting up spandboxed process...");
+
+        info!("Setting up User namespace...");
+        setup_user_namespace(uid, gid)?;
+
+        info!("Setting up Mount namespace...");
+        setup_mount_namespace()?;
+
+        info!("Mounting Sandbox...");
+        mount_sandbox(&self.sandbox_location)?;
+        mount_dev()?;
+
+        info!("Mounting Proc...");
+        mount_proc()?;
+
+        info!("Mounting all input files...");
+        create_symlinks(&self.image_symlinks)?;
+        mount_mounts(&self.sandbox_location, &self.input_files, true)?;
+        mount_mounts(&self.sandbox_location, &self.writable_mounts, false)?;
+        create_symlinks(&self.input_symlinks)?;
+        info!("Change Root...");
+        change_root()?;
+        if !self.working_directory.as_os_str().is_empty() {
+            std::fs::create_dir_all(&self.working_directory)?;
+            std::env::set_current_dir(&self.working_directory)?;
+        }
+
+        unsafe {
+            libc::setpgid(0, 0);
+            libc::umask(0o022);
+
+            // Redirect stderr/stdout to a file
+            err_check(libc::dup2(stderr, libc::STDERR_FILENO))?;
+            err_check(libc::dup2(stdout, libc::STDOUT_FILENO))?;
+        }
+        Ok(())
+    }
+
+    /// Start the action, returning it with the pipe its sandbox reports setup failures on.
+    fn start(&mut self) -> Result<(AsyncSandboxedAction, OwnedFd), SandboxError> {
+        let program = to_cstring(&self.program)?;
+        info!("cmd: {:?}", program);
+        let args = self
+            .arguments
+            .iter()
+            .map(to_cstring)
+            .collect::<Result<Vec<CString>, _>>()?;
+        info!("args: {:?}", args);
+        let mut argv = args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .collect::<Vec<*const libc::c_char>>();
+        argv.push(std::ptr::null());
+
+        let env = self
+            .environment
+            .iter()
+            .map(|arg| format!("{}={}", arg.0, arg.1))
+            .map(|arg| to_cstring(&arg))
+            .collect::<Result<Vec<CString>, _>>()?;
+        info!("env: {:?}", env);
+        let mut envv = env
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .collect::<Vec<*const libc::c_char>>();
+        envv.push(std::ptr::null());
+
+        let stdout = open_log(&self.stdout)?;
+        let stderr = open_log(&self.stderr)?;
         let uid = unsafe { libc::getuid() };
         let gid = unsafe { libc::getgid() };
-        let (child_pid, pid_fd) = clone3()?;
+        let spawning = SPAWN_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let (report_rx, report_tx) = cloexec_pipe()?;
+        let (child_pid, pid_fd) = clone3(self.network)?;
         let child_span = span!(Level::INFO, "sandbox_process");
         if child_pid == 0 {
+            drop(report_rx);
+            let (kind, err) = child_span.in_scope(|| {
+                if let Err(err) =
+                    self.enter_sandbox(uid, gid, stdout.as_raw_fd(), stderr.as_raw_fd())
+                {
+                    return (SETUP_FAILED, err);
+                }
+                // start the worker process, only returns on failure
+                let err = unsafe {
+                    err_check(libc::execvpe(
+                        program.as_ptr(),
+                        argv.as_ptr(),
+                        envv.as_ptr(),
+                    ))
+                };
+                (
+                    EXEC_FAILED,
+                    err.err().unwrap_or_else(io::Error::last_os_error),
+                )
+            });
+            let mut report = vec![kind];
+            report.extend_from_slice(err.to_string().as_bytes());
             unsafe {
-                // Kill with SIGKILL if Parent dies
-                err_check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+                libc::write(
+                    report_tx.as_raw_fd(),
+                    report.as_ptr() as *const libc::c_void,
+                    report.len(),
+                );
+                libc::_exit(127);
             }
+        }
 
-            child_span.in_scope(|| {
-                info!("Setting up spandboxed process...");
-
-                info!("Setting up User namespace...");
-                setup_user_namespace(uid, gid)?;
-
-                info!("Setting up Mount namespace...");
-                setup_mount_namespace()?;
-
-                info!("Mounting Sandbox...");
-                mount_sandbox(Path::new("/home/ben/workspace/gaudi/sandbox"))?;
-                mount_dev()?;
-
-                info!("Mounting Proc...");
-                mount_proc()?;
-
-                info!("Mounting all input files...");
-                mount_mounts(
-                    Path::new("/home/ben/workspace/gaudi/sandbox"),
-                    &self.input_files,
-                    true,
-                )?;
-                info!("Mounting all output files...");
-                mount_mounts(
-                    Path::new("/home/ben/workspace/gaudi/sandbox"),
-                    &self.output_files_map,
-                    false,
-                )?;
-                info!("Change Root...");
-                change_root()?;
-
-                unsafe {
-                    let c = CString::new(self.program.as_str())?;
-                    info!("cmd: {:?}", c);
-                    let args = self
-                        .arguments
-                        .iter()
-                        .map(|arg| CString::new(arg.as_str()).unwrap())
-                        .collect::<Vec<CString>>();
-                    info!("args: {:?}", args);
-                    let mut argv = args
-                        .iter()
-                        .map(|arg| arg.as_ptr())
-                        .collect::<Vec<*const libc::c_char>>();
-                    argv.push(std::ptr::null());
-
-                    let env = self
-                        .environment
-                        .iter()
-                        .map(|arg| format!("{}={}", arg.0, arg.1))
-                        .map(|arg| CString::new(arg.as_str()).unwrap())
-                        .collect::<Vec<CString>>();
-                    info!("env: {:?}", env);
-                    let mut envv = env
-                        .iter()
-                        .map(|arg| arg.as_ptr())
-                        .collect::<Vec<*const libc::c_char>>();
-                    envv.push(std::ptr::null());
-
-                    // Create directories for all output files
-                    for output in self.output_files.iter() {
-                        if let Some(prefix) = output.parent() {
-                            std::fs::create_dir_all(prefix).unwrap();
-                        }
-                    }
-                    libc::setpgid(0, 0);
-                    libc::umask(022);
-
-                    // Redirect stderr/stdout to a file
-                    err_check(libc::dup2(self.stderr.1, libc::STDERR_FILENO))?;
-                    err_check(libc::dup2(self.stdout.1, libc::STDOUT_FILENO))?;
-
-                    // start the worker process
-                    err_check(libc::execvpe(c.as_ptr(), argv.as_ptr(), envv.as_ptr()))?;
-                }
+        drop(report_tx);
+        drop(spawning);
+        let inner = AsyncFd::new(unsafe { OwnedFd::from_raw_fd(pid_fd) })?;
+        let action = AsyncSandboxedAction {
+            inner,
+            output_files: self.output_files.clone(),
+            output_directories: self.output_directories.clone(),
+            output_paths: self.output_paths.clone(),
+            stderr: self.stderr.clone(),
+            stdout: self.stdout.clone(),
+            exited: AtomicBool::new(false),
+        };
+        Ok((action, report_rx))
+    }
 
-                // just used for type annotations, execvp/pidfd
-                // changed how this whole thing returns
-                unreachable!();
-                #[allow(unreachable_code)]
-                Err(io::Error::new(io::ErrorKind::Other, ""))
-            })?
-        } else {
-            let inner = AsyncFd::new(pid_fd)?;
-            Ok(AsyncSandboxedAction {
-                inner,
-                output_files_map: self.output_files_map.clone(),
-                stderr: self.stderr.0.clone(),
-                stdout: self.stdout.0.clone(),
-            })
+    #[instrument(skip_all)]
+    pub async fn spawn(&mut self) -> Result<AsyncSandboxedAction, SandboxError> {
+        let (action, report_rx) = self.start()?;
+        // The pipe closes on a successful exec, otherwise the sandbox says what went wrong.
+        // Dropping the action on failure reaps the sandbox process.
+        let report = tokio::task::spawn_blocking(move || {
+            let mut report = vec![];
+            std::fs::File::from(report_rx)
+                .read_to_end(&mut report)
+                .map(|_| report)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        match report.split_first() {
+            None => Ok(action),
+            Some((&EXEC_FAILED, msg)) => Err(SandboxError::Exec(
+                self.program.clone(),
+                String::from_utf8_lossy(msg).into_owned(),
+            )),
+            Some((_, msg)) => Err(SandboxError::Setup(
+                String::from_utf8_lossy(msg).into_owned(),
+            )),
         }
     }
 }
@@ -296,8 +555,19 @@ fn setup_mount_namespace() -> io::Result<()> {
     Ok(())
 }
 
+/// Opened before the sandbox is entered, the path may not be visible from inside of it.
+fn open_log(path: &Path) -> io::Result<std::fs::File> {
+    if path.as_os_str().is_empty() {
+        return std::fs::OpenOptions::new().write(true).open("/dev/null");
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
 fn mount_sandbox(path: &Path) -> io::Result<()> {
-    let target = CString::new(path.to_str().unwrap())?;
+    let target = path_to_cstring(&path)?;
     err_check(unsafe {
         libc::mount(
             target.as_ptr(),
@@ -319,7 +589,7 @@ fn mount_dev() -> io::Result<()> {
     std::fs::create_dir_all("dev")?;
     std::fs::File::create("dev/null")?;
     for dev in ["/dev/null"].map(PathBuf::from) {
-        let dev_mnt = path_to_cstring(&dev).unwrap();
+        let dev_mnt = path_to_cstring(&dev)?;
         err_check(unsafe {
             libc::mount(
                 dev_mnt.as_ptr(),
@@ -335,7 +605,7 @@ fn mount_dev() -> io::Result<()> {
 fn mount_proc() -> io::Result<()> {
     let proc_mnt = CString::new("/proc")?;
     let proc = CString::new("proc")?;
-    Ok(err_check(unsafe {
+    err_check(unsafe {
         libc::mount(
             proc_mnt.as_ptr(),
             proc_mnt.as_ptr(),
@@ -343,10 +613,10 @@ fn mount_proc() -> io::Result<()> {
             libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID,
             ptr::null(),
         )
-    })?)
+    })
 }
 
-fn mount_mounts(path: &Path, mount_mapping: &[Mapping], readonly: bool) -> io::Result<()> {
+fn mount_mounts(root: &Path, mount_mapping: &[Mapping], readonly: bool) -> io::Result<()> {
     for mount in mount_mapping {
         info!(
             "Binding {} -> {}",
@@ -354,42 +624,119 @@ fn mount_mounts(path: &Path, mount_mapping: &[Mapping], readonly: bool) -> io::R
             mount.dest_path.display()
         );
 
+        let inside = |path: &Path| Path::new("/").join(path.strip_prefix(root).unwrap_or(path));
+        // The image's links already exist, following one could bind outside of the sandbox
+        if let Some(link) = mount
+            .dest_path
+            .ancestors()
+            .take_while(|path| *path != root)
+            .find(|path| path.is_symlink())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot mount at {}, {} is a symlink in the image",
+                    inside(&mount.dest_path).display(),
+                    inside(link).display()
+                ),
+            ));
+        }
+
         // create a file to bind against at the depth
-        if mount.source_path.is_file() {
-            if let Some(prefix) = mount.dest_path.parent() {
-                std::fs::create_dir_all(prefix)?;
+        let created = if mount.source_path.is_file() {
+            match mount.dest_path.parent() {
+                Some(prefix) => std::fs::create_dir_all(prefix),
+                None => Ok(()),
             }
-            std::fs::File::create(&mount.dest_path)?;
+            .and_then(|_| match mount.dest_path.exists() {
+                true => Ok(()),
+                false => std::fs::File::create(&mount.dest_path).map(drop),
+            })
         } else {
-            std::fs::create_dir_all(&mount.dest_path)?;
-        }
+            std::fs::create_dir_all(&mount.dest_path)
+        };
+        // Made in the image they would show up for every action using it
+        created.map_err(|e| match e.raw_os_error() {
+            Some(libc::EROFS) => io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot mount at {}, it is missing from the read-only image",
+                    inside(&mount.dest_path).display()
+                ),
+            ),
+            _ => e,
+        })?;
 
-        let src = path_to_cstring(&mount.source_path).unwrap();
-        let target = path_to_cstring(&mount.dest_path).unwrap();
+        let src = path_to_cstring(&mount.source_path)?;
+        let target = path_to_cstring(&mount.dest_path)?;
         err_check(unsafe {
             libc::mount(
                 src.as_ptr(),
                 target.as_ptr(),
                 ptr::null(),
-                if readonly {
-                    libc::MS_BIND | libc::MS_REC | libc::MS_RDONLY
-                } else {
-                    libc::MS_BIND | libc::MS_REC
-                },
+                libc::MS_BIND | libc::MS_REC,
                 ptr::null(),
             )
         })?;
+        if readonly {
+            remount_readonly(&target)?;
+        }
     }
 
     Ok(())
 }
+
+/// A bind mount only becomes read-only through a remount, which must keep the flags the kernel
+/// locked on the original mount or it is refused inside a user namespace.
+fn remount_readonly(target: &CString) -> io::Result<()> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    err_check(unsafe { libc::statvfs(target.as_ptr(), &mut stat) })?;
+    let mut flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY;
+    for (locked, flag) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & locked != 0 {
+            flags |= flag;
+        }
+    }
+    err_check(unsafe {
+        libc::mount(
+            ptr::null(),
+            target.as_ptr(),
+            ptr::null(),
+            flags,
+            ptr::null(),
+        )
+    })
+}
+fn create_symlinks(symlinks: &[Symlink]) -> io::Result<()> {
+    for symlink in symlinks {
+        info!(
+            "Linking {} -> {}",
+            symlink.path.display(),
+            symlink.target.display()
+        );
+        if let Some(prefix) = symlink.path.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+        remove_path(&symlink.path)?;
+        std::os::unix::fs::symlink(&symlink.target, &symlink.path)?;
+    }
+    Ok(())
+}
+
 fn change_root() -> io::Result<()> {
     // make a old root to swap with
     let mut temp = CString::new("old-root-XXXXXX")?;
     unsafe {
         let temp_ptr = temp.into_raw();
         let ret = libc::mkdtemp(temp_ptr);
-        if ret == ptr::null_mut() {
+        if ret.is_null() {
             return Err(io::Error::last_os_error());
         }
         temp = CString::from_raw(temp_ptr);
@@ -410,10 +757,81 @@ fn change_root() -> io::Result<()> {
     Ok(())
 }
 
-pub fn path_to_cstring<P: AsRef<Path>>(path: &P) -> Option<CString> {
+/// Resolve `.` and `..` in a path relative to the input root, `None` if it leaves the root.
+pub fn resolve_in_root(path: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Fail if a directory between `root` and `path` is a symlink, following it from outside of the
+/// sandbox could reach anywhere on the host.
+pub fn check_parents(root: &Path, path: &Path) -> io::Result<()> {
+    for parent in path
+        .ancestors()
+        .skip(1)
+        .take_while(|parent| *parent != root)
+    {
+        match std::fs::symlink_metadata(parent) {
+            Ok(meta) if meta.is_symlink() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is a symlink", parent.display()),
+                ))
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Remove whatever is at `path`, without following symlinks.
+pub fn remove_path(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn to_cstring<S: AsRef<str>>(s: S) -> Result<CString, SandboxError> {
+    CString::new(s.as_ref()).map_err(|_| SandboxError::InvalidCommand(s.as_ref().to_string()))
+}
+
+/// Held from creating a sandbox's report pipe until the parent closed its end of it, so
+/// sandboxes cloned meanwhile do not inherit the pipe and keep it open until they exec.
+static SPAWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Pipe whose ends close on exec, returned as (read, write).
+fn cloexec_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [-1; 2];
+    err_check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+pub fn path_to_cstring<P: AsRef<Path>>(path: &P) -> io::Result<CString> {
     path.as_ref()
         .to_str()
         .and_then(|p| std::ffi::CString::new(p).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unusable path: {}", path.as_ref().display()),
+            )
+        })
 }
 
 /// Turn the unix error codes into something more Rust-y
@@ -0,0 +1,707 @@
+//! Hands actions to workers connecting through the Remote Workers API.
+//!
+//! Every worker keeps a bot session advertising the platform properties it supports and how many
+//! actions it runs at once. Its updates report on the leases it holds and pick up the most urgent
+//! waiting actions its platform can run. Sessions that stop calling in are given up on, their
+//! actions go back to waiting for another worker. Actions no open session can run fail once
+//! that has lasted as long as a worker may go quiet.
+
+use crate::api::{self, remoteworkers};
+use crate::execution_queue::effective_priority;
+use crate::platform::{Platform, PlatformConfig, PropertyConfig};
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::sync::futures::Notified;
+use tokio::sync::{oneshot, watch, Notify};
+use tracing::info;
+use uuid::Uuid;
+
+/// Worker property holding the number of actions the worker runs at once, one if left out.
+pub const SLOTS: &str = "slots";
+/// Times an action is handed out before the scheduler stops blaming the workers running it.
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum SchedulerError {
+    #[error("Unknown bot session {0}")]
+    UnknownSession(String),
+
+    #[error("Invalid worker: {0}")]
+    InvalidWorker(String),
+}
+
+impl From<SchedulerError> for tonic::Status {
+    fn from(err: SchedulerError) -> Self {
+        match err {
+            SchedulerError::UnknownSession(_) => tonic::Status::not_found(err.to_string()),
+            SchedulerError::InvalidWorker(_) => tonic::Status::invalid_argument(err.to_string()),
+        }
+    }
+}
+
+/// An action waiting for a worker.
+struct Pending {
+    lease_id: String,
+    priority: i32,
+    seq: u64,
+    /// When the action first started waiting, it ages from there even when requeued.
+    enqueued: Instant,
+    /// Since when no open session could run the action.
+    unmatched_since: Option<Instant>,
+    /// Workers the action was lost on so far.
+    attempts: u32,
+    /// Properties the action asked for, a worker has to support all of them.
+    platform: Platform,
+    assignment: api::Assignment,
+    tx: oneshot::Sender<api::ExecuteResponse>,
+    /// Whether a worker holds the action right now.
+    leased: watch::Sender<bool>,
+}
+
+/// An action handed to a worker.
+struct Leased {
+    pending: Pending,
+    leased_at: SystemTime,
+}
+
+struct Session {
+    bot_id: String,
+    /// As the worker advertised itself.
+    worker: remoteworkers::Worker,
+    platform: PlatformConfig,
+    slots: usize,
+    leases: Vec<Leased>,
+    last_seen: Instant,
+    completed: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_seq: u64,
+    pending: Vec<Pending>,
+    /// By session name.
+    sessions: HashMap<String, Session>,
+    /// Waiting time that earns a queued action one step of priority.
+    aging: Duration,
+}
+
+impl Inner {
+    /// Put an action a worker lost back in line, in its old place. Returns whether it waits
+    /// again, an action lost too often fails instead.
+    fn requeue(&mut self, mut pending: Pending, reason: String) -> bool {
+        pending.attempts += 1;
+        if pending.attempts >= MAX_ATTEMPTS {
+            info!("Giving up on lease {}: {}", pending.lease_id, reason);
+            let _ = pending.tx.send(failure(
+                tonic::Code::Unavailable,
+                format!("{}, gave up after {} attempts", reason, pending.attempts),
+            ));
+            return false;
+        }
+        info!("Requeueing lease {}: {}", pending.lease_id, reason);
+        // A fresh id keeps late reports from the old worker apart
+        pending.lease_id = Uuid::new_v4().to_string();
+        let _ = pending.leased.send(false);
+        self.pending.push(pending);
+        true
+    }
+
+    /// Drop the sessions of workers that have not called in for `timeout`. Returns whether
+    /// any of their actions wait again.
+    fn expire(&mut self, timeout: Duration) -> bool {
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.last_seen.elapsed() > timeout)
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut requeued = false;
+        for name in expired {
+            let session = self.sessions.remove(&name).unwrap();
+            info!(
+                "Bot {} stopped calling in, closing {}",
+                session.bot_id, name
+            );
+            for leased in session.leases {
+                requeued |= self.requeue(
+                    leased.pending,
+                    format!("worker {} stopped responding", session.bot_id),
+                );
+            }
+        }
+        requeued
+    }
+
+    /// Fail the actions no open session could run for `timeout`.
+    fn fail_unmatched(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        for pending in &mut self.pending {
+            let matched = self
+                .sessions
+                .values()
+                .any(|session| session.platform.check(&pending.platform).is_ok());
+            match matched {
+                true => pending.unmatched_since = None,
+                false => {
+                    pending.unmatched_since.get_or_insert(now);
+                }
+            }
+        }
+        let (unmatched, waiting): (Vec<Pending>, Vec<Pending>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| {
+                pending
+                    .unmatched_since
+                    .is_some_and(|since| now.duration_since(since) >= timeout)
+            });
+        self.pending = waiting;
+        for pending in unmatched {
+            info!("No worker can run lease {}", pending.lease_id);
+            let _ = pending.tx.send(failure(
+                tonic::Code::FailedPrecondition,
+                format!("no worker supports the platform {}", pending.platform),
+            ));
+        }
+    }
+}
+
+/// Remove the most urgent waiting action `platform` can run: lowest priority value after
+/// aging, oldest first.
+fn pop_next(
+    pending: &mut Vec<Pending>,
+    platform: &PlatformConfig,
+    aging: Duration,
+) -> Option<Pending> {
+    // Actions cancelled while waiting leave their place behind
+    pending.retain(|pending| !pending.tx.is_closed());
+    let now = Instant::now();
+    let next = pending
+        .iter()
+        .enumerate()
+        .filter(|(_, pending)| platform.check(&pending.platform).is_ok())
+        .min_by_key(|(_, pending)| {
+            let priority = effective_priority(pending.priority, pending.enqueued, now, aging);
+            (priority, pending.seq)
+        })
+        .map(|(i, _)| i)?;
+    Some(pending.swap_remove(next))
+}
+
+/// The platform a worker supports, as listed in its configs. Each config is one accepted value
+/// of a property, an empty value accepts any.
+fn advertised_platform(worker: &remoteworkers::Worker) -> PlatformConfig {
+    let mut properties: BTreeMap<String, (PropertyConfig, bool)> = BTreeMap::new();
+    for config in &worker.configs {
+        let (property, any) = properties.entry(config.key.clone()).or_default();
+        if config.value.is_empty() {
+            *any = true;
+        } else {
+            property.values.push(config.value.clone());
+        }
+    }
+    PlatformConfig::new(
+        properties
+            .into_iter()
+            .map(|(name, (property, any))| match any {
+                true => (name, PropertyConfig::default()),
+                false => (name, property),
+            })
+            .collect(),
+    )
+}
+
+fn advertised_slots(worker: &remoteworkers::Worker) -> Result<usize, SchedulerError> {
+    let Some(property) = worker
+        .properties
+        .iter()
+        .rfind(|property| property.key == SLOTS)
+    else {
+        return Ok(1);
+    };
+    match property.value.parse() {
+        Ok(slots) if slots > 0 => Ok(slots),
+        _ => Err(SchedulerError::InvalidWorker(format!(
+            "{} must be a positive number, not {:?}",
+            SLOTS, property.value
+        ))),
+    }
+}
+
+fn failure(code: tonic::Code, message: String) -> api::ExecuteResponse {
+    api::ExecuteResponse {
+        status: Some(api::Status {
+            code: code as i32,
+            message,
+            details: vec![],
+        }),
+        ..Default::default()
+    }
+}
+
+/// The outcome a worker reported for a lease it finished or gave up on.
+fn lease_response(bot_id: &str, lease: &remoteworkers::Lease) -> api::ExecuteResponse {
+    // A failed lease means the worker could not take the action on at all
+    if let Some(status) = lease.status.as_ref().filter(|status| status.code != 0) {
+        return api::ExecuteResponse {
+            status: Some(status.clone()),
+            ..Default::default()
+        };
+    }
+    match lease
+        .result
+        .as_ref()
+        .map(|result| api::ExecuteResponse::decode(&result.value[..]))
+    {
+        Some(Ok(response)) => response,
+        _ => failure(
+            tonic::Code::Internal,
+            format!("worker {} returned no result", bot_id),
+        ),
+    }
+}
+
+fn reply(id: String, state: remoteworkers::LeaseState) -> remoteworkers::Lease {
+    remoteworkers::Lease {
+        id,
+        state: state as i32,
+        ..Default::default()
+    }
+}
+
+/// The actions waiting for workers and the sessions of the workers running them.
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Mutex<Inner>>,
+    /// Woken whenever an action starts waiting.
+    queued: Arc<Notify>,
+    /// How long a worker may go without calling in before its actions are handed to others.
+    worker_timeout: Duration,
+}
+
+impl Scheduler {
+    pub fn new(worker_timeout: Duration, aging: Duration) -> Self {
+        Scheduler {
+            inner: Arc::new(Mutex::new(Inner {
+                aging,
+                ..Default::default()
+            })),
+            queued: Arc::new(Notify::new()),
+            worker_timeout,
+        }
+    }
+
+    pub fn worker_timeout(&self) -> Duration {
+        self.worker_timeout
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Check on workers and waiting actions in the background, independently of bots calling
+    /// in: expired sessions give their actions back and actions nobody can run fail.
+    pub fn spawn_sweeper(&self) {
+        let scheduler = self.clone();
+        let period = (self.worker_timeout / 4).max(Duration::from_millis(100));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let mut inner = scheduler.lock();
+                if inner.expire(scheduler.worker_timeout) {
+                    scheduler.queued.notify_waiters();
+                }
+                inner.fail_unmatched(scheduler.worker_timeout);
+            }
+        });
+    }
+
+    /// Wait for a worker supporting `platform` to run the assignment. Lower `priority` values
+    /// are handed out first. `on_lease` hears whenever a worker takes the action on or loses it.
+    ///
+    /// Dropping the future withdraws the action, a worker already running it is told to stop.
+    pub async fn run(
+        &self,
+        assignment: api::Assignment,
+        priority: i32,
+        platform: Platform,
+        mut on_lease: impl FnMut(bool),
+    ) -> api::ExecuteResponse {
+        let (tx, mut rx) = oneshot::channel();
+        let (leased, mut leased_rx) = watch::channel(false);
+        {
+            let mut inner = self.lock();
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.pending.push(Pending {
+                lease_id: Uuid::new_v4().to_string(),
+                priority,
+                seq,
+                enqueued: Instant::now(),
+                unmatched_since: None,
+                attempts: 0,
+                platform,
+                assignment,
+                tx,
+                leased,
+            });
+        }
+        self.queued.notify_waiters();
+        let dropped = |_| {
+            failure(
+                tonic::Code::Internal,
+                String::from("action was dropped by the scheduler"),
+            )
+        };
+        loop {
+            tokio::select! {
+                response = &mut rx => return response.unwrap_or_else(dropped),
+                changed = leased_rx.changed() => match changed {
+                    Ok(()) => on_lease(*leased_rx.borrow()),
+                    // Done, the response is on its way
+                    Err(_) => return rx.await.unwrap_or_else(dropped),
+                },
+            }
+        }
+    }
+
+    /// Resolves once another action starts waiting for a worker.
+    pub fn queued(&self) -> Notified<'_> {
+        self.queued.notified()
+    }
+
+    /// Open a session for `bot_id`, replacing any session the bot had before.
+    pub fn create_session(
+        &self,
+        parent: &str,
+        bot_id: &str,
+        worker: remoteworkers::Worker,
+    ) -> Result<String, SchedulerError> {
+        let slots = advertised_slots(&worker)?;
+        let name = if parent.is_empty() {
+            format!("botSessions/{}", Uuid::new_v4())
+        } else {
+            format!(
+                "{}/botSessions/{}",
+                parent.trim_end_matches('/'),
+                Uuid::new_v4()
+            )
+        };
+        let mut inner = self.lock();
+        // A restarted bot forgot whatever it was running
+        let replaced: Vec<String> = inner
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.bot_id == bot_id)
+            .map(|(old, _)| old.clone())
+            .collect();
+        let mut requeued = false;
+        for old in replaced {
+            info!("Bot session {} replaced by {}", old, name);
+            let session = inner.sessions.remove(&old).unwrap();
+            for leased in session.leases {
+                requeued |= inner.requeue(
+                    leased.pending,
+                    format!("worker {} restarted while running the action", bot_id),
+                );
+            }
+        }
+        if requeued {
+            self.queued.notify_waiters();
+        }
+        info!(
+            "Bot {} opened session {} with {} slots",
+            bot_id, name, slots
+        );
+        inner.sessions.insert(
+            name.clone(),
+            Session {
+                bot_id: bot_id.to_string(),
+                platform: advertised_platform(&worker),
+                worker,
+                slots,
+                leases: vec![],
+                last_seen: Instant::now(),
+                completed: 0,
+            },
+        );
+        Ok(name)
+    }
+
+    /// Take in the leases a bot reports, returning what it should do about each of them.
+    pub fn report(
+        &self,
+        name: &str,
+        leases: Vec<remoteworkers::Lease>,
+    ) -> Result<Vec<remoteworkers::Lease>, SchedulerError> {
+        use remoteworkers::LeaseState;
+
+        let mut inner = self.lock();
+        let session = inner
+            .sessions
+            .get_mut(name)
+            .ok_or_else(|| SchedulerError::UnknownSession(name.to_string()))?;
+        session.last_seen = Instant::now();
+        let mut replies = vec![];
+        let mut mentioned = vec![];
+        for lease in leases {
+            let state = LeaseState::from_i32(lease.state).unwrap_or(LeaseState::Unspecified);
+            let Some(i) = session
+                .leases
+                .iter()
+                .position(|leased| leased.pending.lease_id == lease.id)
+            else {
+                // Withdrawn or never handed out, the bot should let go of it
+                if matches!(state, LeaseState::Pending | LeaseState::Active) {
+                    replies.push(reply(lease.id, LeaseState::Cancelled));
+                }
+                continue;
+            };
+            match state {
+                LeaseState::Completed | LeaseState::Cancelled => {
+                    let leased = session.leases.swap_remove(i);
+                    info!("Bot {} finished lease {}", session.bot_id, lease.id);
+                    session.completed += 1;
+                    let _ = leased
+                        .pending
+                        .tx
+                        .send(lease_response(&session.bot_id, &lease));
+                }
+                _ if session.leases[i].pending.tx.is_closed() => {
+                    info!("Lease {} was cancelled", lease.id);
+                    session.leases.swap_remove(i);
+                    replies.push(reply(lease.id, LeaseState::Cancelled));
+                }
+                _ => {
+                    mentioned.push(lease.id.clone());
+                    replies.push(reply(lease.id, LeaseState::Active));
+                }
+            }
+        }
+        // Every update lists all leases, one left out never reached the bot or was forgotten
+        let (kept, lost): (Vec<Leased>, Vec<Leased>) = std::mem::take(&mut session.leases)
+            .into_iter()
+            .partition(|leased| mentioned.contains(&leased.pending.lease_id));
+        session.leases = kept;
+        let bot_id = session.bot_id.clone();
+        let mut requeued = false;
+        for leased in lost {
+            requeued |= inner.requeue(leased.pending, format!("worker {} lost the action", bot_id));
+        }
+        if requeued {
+            self.queued.notify_waiters();
+        }
+        Ok(replies)
+    }
+
+    /// Lease waiting actions to the session until its slots are full.
+    pub fn assign(&self, name: &str) -> Result<Vec<remoteworkers::Lease>, SchedulerError> {
+        let mut inner = self.lock();
+        let Inner {
+            pending,
+            sessions,
+            aging,
+            ..
+        } = &mut *inner;
+        let session = sessions
+            .get_mut(name)
+            .ok_or_else(|| SchedulerError::UnknownSession(name.to_string()))?;
+        session.last_seen = Instant::now();
+        let mut leases = vec![];
+        while session.leases.len() < session.slots {
+            let Some(next) = pop_next(pending, &session.platform, *aging) else {
+                break;
+            };
+            info!("Leasing {} to bot {}", next.lease_id, session.bot_id);
+            let _ = next.leased.send(true);
+            leases.push(remoteworkers::Lease {
+                id: next.lease_id.clone(),
+                payload: Some(prost_types::Any {
+                    type_url: String::from("type.googleapis.com/gaudi.worker.v1.Assignment"),
+                    value: next.assignment.encode_to_vec(),
+                }),
+                state: remoteworkers::LeaseState::Pending as i32,
+                ..Default::default()
+            });
+            session.leases.push(Leased {
+                pending: next,
+                leased_at: SystemTime::now(),
+            });
+        }
+        Ok(leases)
+    }
+
+    /// The state of every worker with an open session.
+    pub fn workers(&self) -> api::worker::ListWorkersResponse {
+        let mut inner = self.lock();
+        inner.pending.retain(|pending| !pending.tx.is_closed());
+        let now = SystemTime::now();
+        let mut workers: Vec<api::worker::WorkerState> = inner
+            .sessions
+            .iter()
+            .map(|(name, session)| api::worker::WorkerState {
+                bot_id: session.bot_id.clone(),
+                session: name.clone(),
+                worker: Some(session.worker.clone()),
+                slots: session.slots as u32,
+                leases: session
+                    .leases
+                    .iter()
+                    .map(|leased| api::worker::LeaseState {
+                        lease_id: leased.pending.lease_id.clone(),
+                        action_digest: leased.pending.assignment.action_digest.clone(),
+                        leased_at: Some(leased.leased_at.into()),
+                    })
+                    .collect(),
+                last_seen: Some((now - session.last_seen.elapsed()).into()),
+                completed_leases: session.completed,
+            })
+            .collect();
+        workers.sort_by(|a, b| a.bot_id.cmp(&b.bot_id));
+        api::worker::ListWorkersResponse {
+            workers,
+            queued_actions: inner.pending.len() as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AGING: Duration = Duration::from_secs(1);
+
+    fn pending(
+        seq: u64,
+        priority: i32,
+        properties: &[(&str, &str)],
+    ) -> (Pending, oneshot::Receiver<api::ExecuteResponse>) {
+        let platform = api::Platform {
+            properties: properties
+                .iter()
+                .map(|(name, value)| api::platform::Property {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        };
+        let (tx, rx) = oneshot::channel();
+        let pending = Pending {
+            lease_id: Uuid::new_v4().to_string(),
+            priority,
+            seq,
+            enqueued: Instant::now(),
+            unmatched_since: None,
+            attempts: 0,
+            platform: Platform::requested(Some(&platform), None).unwrap(),
+            assignment: api::Assignment::default(),
+            tx,
+            leased: watch::channel(true).0,
+        };
+        (pending, rx)
+    }
+
+    fn worker(configs: &[(&str, &str)]) -> remoteworkers::Worker {
+        remoteworkers::Worker {
+            configs: configs
+                .iter()
+                .map(|(key, value)| remoteworkers::worker::Config {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn seqs(mut waiting: Vec<Pending>, platform: &PlatformConfig) -> Vec<u64> {
+        std::iter::from_fn(|| pop_next(&mut waiting, platform, AGING))
+            .map(|pending| pending.seq)
+            .collect()
+    }
+
+    #[test]
+    fn pop_most_urgent_first() {
+        let (a, _a) = pending(0, 5, &[]);
+        let (b, _b) = pending(1, 1, &[]);
+        let (c, _c) = pending(2, 1, &[]);
+        assert_eq!(seqs(vec![a, b, c], &PlatformConfig::default()), [1, 2, 0]);
+    }
+
+    #[test]
+    fn pop_only_what_the_worker_supports() {
+        let platform = advertised_platform(&worker(&[("network", "off"), ("toolchain", "")]));
+        let (network, _network) = pending(0, 0, &[("network", "on")]);
+        let (offline, _offline) = pending(1, 5, &[("network", "off"), ("toolchain", "gcc")]);
+        let mut waiting = vec![network, offline];
+        assert_eq!(
+            pop_next(&mut waiting, &platform, AGING).map(|pending| pending.seq),
+            Some(1)
+        );
+        assert!(pop_next(&mut waiting, &platform, AGING).is_none());
+        assert_eq!(waiting.len(), 1);
+    }
+
+    #[test]
+    fn pop_aged_actions_first() {
+        let (mut old, _old) = pending(0, 10, &[]);
+        old.enqueued -= AGING * 20;
+        let (new, _new) = pending(1, 0, &[]);
+        assert_eq!(seqs(vec![new, old], &PlatformConfig::default()), [0, 1]);
+    }
+
+    #[test]
+    fn pop_skips_cancelled_actions() {
+        let (cancelled, rx) = pending(0, 0, &[]);
+        drop(rx);
+        let mut waiting = vec![cancelled];
+        assert!(pop_next(&mut waiting, &PlatformConfig::default(), AGING).is_none());
+        assert!(waiting.is_empty());
+    }
+
+    #[test]
+    fn requeue_until_max_attempts() {
+        let mut inner = Inner::default();
+        let (mut lost, mut rx) = pending(0, 0, &[]);
+        let leased = lost.leased.subscribe();
+        for attempt in 1..MAX_ATTEMPTS {
+            let lease_id = lost.lease_id.clone();
+            assert!(inner.requeue(lost, String::from("worker went away")));
+            lost = inner.pending.pop().unwrap();
+            assert_eq!(lost.attempts, attempt);
+            assert_ne!(lost.lease_id, lease_id);
+            assert!(!*leased.borrow());
+        }
+        assert!(!inner.requeue(lost, String::from("worker went away")));
+        assert!(inner.pending.is_empty());
+        let status = rx.try_recv().unwrap().status.unwrap();
+        assert_eq!(status.code, tonic::Code::Unavailable as i32);
+    }
+
+    #[test]
+    fn fail_actions_no_worker_supports() {
+        let mut inner = Inner::default();
+        let (unmatched, mut rx) = pending(0, 0, &[("network", "on")]);
+        inner.pending.push(unmatched);
+        inner.fail_unmatched(Duration::from_secs(60));
+        assert_eq!(inner.pending.len(), 1);
+        inner.fail_unmatched(Duration::ZERO);
+        assert!(inner.pending.is_empty());
+        let status = rx.try_recv().unwrap().status.unwrap();
+        assert_eq!(status.code, tonic::Code::FailedPrecondition as i32);
+    }
+
+    #[test]
+    fn advertised_slots_default_to_one() {
+        assert_eq!(advertised_slots(&worker(&[])).unwrap(), 1);
+        let mut busy = worker(&[]);
+        busy.properties.push(remoteworkers::worker::Property {
+            key: String::from(SLOTS),
+            value: String::from("0"),
+        });
+        assert!(advertised_slots(&busy).is_err());
+    }
+}
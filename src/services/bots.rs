@@ -0,0 +1,82 @@
+use crate::{
+    api::{self, remoteworkers},
+    scheduler::Scheduler,
+};
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::{Request, Response, Status};
+use tracing::{info, instrument};
+
+/// How long an idle bot's update waits for work before returning without a lease.
+const POLL_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub struct BotsService {
+    scheduler: Scheduler,
+}
+
+impl BotsService {
+    pub fn new(scheduler: Scheduler) -> Self {
+        BotsService { scheduler }
+    }
+}
+
+#[tonic::async_trait]
+impl api::Bots for BotsService {
+    #[instrument(skip_all, fields(parent = request.get_ref().parent))]
+    async fn create_bot_session(
+        &self,
+        request: Request<remoteworkers::CreateBotSessionRequest>,
+    ) -> Result<Response<remoteworkers::BotSession>, Status> {
+        let request = request.into_inner();
+        let session = request
+            .bot_session
+            .ok_or_else(|| Status::invalid_argument("no bot session"))?;
+        if session.bot_id.is_empty() {
+            return Err(Status::invalid_argument("no bot id"));
+        }
+        let name = self.scheduler.create_session(
+            &request.parent,
+            &session.bot_id,
+            session.worker.clone().unwrap_or_default(),
+        )?;
+        Ok(Response::new(remoteworkers::BotSession {
+            name,
+            leases: vec![],
+            ..session
+        }))
+    }
+
+    #[instrument(skip_all, fields(name = request.get_ref().name))]
+    async fn update_bot_session(
+        &self,
+        request: Request<remoteworkers::UpdateBotSessionRequest>,
+    ) -> Result<Response<remoteworkers::BotSession>, Status> {
+        let request = request.into_inner();
+        let mut session = request
+            .bot_session
+            .ok_or_else(|| Status::invalid_argument("no bot session"))?;
+        let reported = std::mem::take(&mut session.leases);
+        let mut leases = self.scheduler.report(&request.name, reported)?;
+
+        // An idle bot is held here until there is something for it to do, but not so long that
+        // it looks gone
+        let deadline = Instant::now() + POLL_TIMEOUT.min(self.scheduler.worker_timeout() / 2);
+        loop {
+            let queued = self.scheduler.queued();
+            let assigned = self.scheduler.assign(&request.name)?;
+            if !assigned.is_empty() {
+                leases.extend(assigned);
+                break;
+            }
+            if !leases.is_empty() || tokio::time::timeout_at(deadline, queued).await.is_err() {
+                break;
+            }
+        }
+        info!("Bot {} holds {} leases", session.bot_id, leases.len());
+        Ok(Response::new(remoteworkers::BotSession {
+            name: request.name,
+            leases,
+            ..session
+        }))
+    }
+}
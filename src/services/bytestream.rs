@@ -1,18 +1,199 @@
-use crate::{api, content_storage::ContentStorage};
+use crate::{
+    api,
+    content_storage::{self, CasError, ContentStorage, CHUNK_SIZE},
+    log_stream::{LogReader, LogStreams},
+};
+use thiserror::Error;
+use tokio::io::AsyncSeekExt;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_stream::StreamExt;
 use tonic::{Request, Response, Status};
 use tracing::{info, instrument};
 
-#[derive(Debug)]
+#[derive(Error, Debug, PartialEq)]
+enum ResourceError {
+    #[error("Not a blob resource name: {0}")]
+    NotBlob(String),
+
+    #[error("Not an upload resource name: {0}")]
+    NotUpload(String),
+
+    #[error("Not a valid uuid: {0}")]
+    Uuid(String),
+
+    #[error("Bad hash value: {0}")]
+    Hash(String),
+
+    #[error("Bad size value: {0}")]
+    Size(String),
+}
+
+impl From<ResourceError> for tonic::Status {
+    fn from(err: ResourceError) -> Self {
+        tonic::Status::invalid_argument(err.to_string())
+    }
+}
+
+/// A blob named by a ByteStream resource.
+#[derive(Debug, PartialEq)]
+struct BlobResource {
+    instance: String,
+    digest: api::Digest,
+}
+
+impl BlobResource {
+    /// `{instance_name}/blobs/{hash}/{size}`, where the instance name can be empty or span
+    /// several segments.
+    fn parse_read(resource_name: &str) -> Result<Self, ResourceError> {
+        let segments: Vec<&str> = resource_name.split('/').collect();
+        match segments.as_slice() {
+            [instance @ .., "blobs", hash, size] => Self::new(instance, hash, size),
+            _ => Err(ResourceError::NotBlob(resource_name.to_string())),
+        }
+    }
+
+    /// `{instance_name}/uploads/{uuid}/blobs/{hash}/{size}`, optionally followed by metadata.
+    fn parse_write(resource_name: &str) -> Result<Self, ResourceError> {
+        let segments: Vec<&str> = resource_name.split('/').collect();
+        // The first `uploads` followed by a blob, later ones can only be metadata
+        let upload = (0..segments.len()).find_map(|i| match &segments[i..] {
+            ["uploads", uuid, "blobs", hash, size, ..] => Some((i, uuid, hash, size)),
+            _ => None,
+        });
+        let Some((i, uuid, hash, size)) = upload else {
+            return Err(ResourceError::NotUpload(resource_name.to_string()));
+        };
+        uuid::Uuid::parse_str(uuid).map_err(|_| ResourceError::Uuid(uuid.to_string()))?;
+        Self::new(&segments[..i], hash, size)
+    }
+
+    fn new(instance: &[&str], hash: &str, size: &str) -> Result<Self, ResourceError> {
+        if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ResourceError::Hash(hash.to_string()));
+        }
+        let size_bytes = size
+            .parse::<u64>()
+            .map_err(|_| ResourceError::Size(size.to_string()))?;
+        Ok(BlobResource {
+            instance: instance.join("/"),
+            digest: api::Digest {
+                hash: hash.to_string(),
+                size_bytes: size_bytes as i64,
+            },
+        })
+    }
+}
+
 pub struct BytestreamService {
     content_store: ContentStorage,
+    log_streams: LogStreams,
 }
 
 impl BytestreamService {
-    pub fn new(content_store: ContentStorage) -> Self {
-        BytestreamService { content_store }
+    pub fn new(content_store: ContentStorage, log_streams: LogStreams) -> Self {
+        BytestreamService {
+            content_store,
+            log_streams,
+        }
+    }
+
+    /// Stream a log to the client as it is written, up to `limit` bytes when non-zero.
+    fn read_log(
+        mut reader: LogReader,
+        limit: i64,
+    ) -> ReceiverStream<Result<api::ReadResponse, Status>> {
+        let (tx, rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            let mut remaining = if limit > 0 {
+                limit as usize
+            } else {
+                usize::MAX
+            };
+            while remaining > 0 {
+                let mut data = match reader.next().await {
+                    Ok(Some(data)) => data,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e.into())).await;
+                        break;
+                    }
+                };
+                data.truncate(remaining);
+                remaining -= data.len();
+                if tx.send(Ok(api::ReadResponse { data })).await.is_err() {
+                    info!("Log reader went away");
+                    break;
+                }
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+
+    /// Append every message of the stream to the log stream behind `resource_name`.
+    async fn write_log(
+        &self,
+        resource_name: &str,
+        mut write_req: api::WriteRequest,
+        stream: &mut tonic::Streaming<api::WriteRequest>,
+    ) -> Result<Response<api::WriteResponse>, Status> {
+        loop {
+            let committed_size = self
+                .log_streams
+                .write(
+                    resource_name,
+                    write_req.write_offset,
+                    &write_req.data,
+                    write_req.finish_write,
+                )
+                .await?;
+            match stream.message().await? {
+                Some(next) if !write_req.finish_write => write_req = next,
+                _ => {
+                    return Ok(Response::new(api::WriteResponse {
+                        committed_size: committed_size as i64,
+                    }))
+                }
+            }
+        }
+    }
+
+    /// Store the blob uploaded by the stream, once it is complete and matches its digest.
+    async fn write_blob(
+        &self,
+        resource: BlobResource,
+        mut write_req: api::WriteRequest,
+        stream: &mut tonic::Streaming<api::WriteRequest>,
+    ) -> Result<Response<api::WriteResponse>, Status> {
+        info!("Writing to blob");
+        let mut blob = self.content_store.blob_writer(&resource.instance).await?;
+        loop {
+            if write_req.write_offset != blob.size() as i64 {
+                return Err(Status::invalid_argument(format!(
+                    "write at offset {} but {} bytes are committed",
+                    write_req.write_offset,
+                    blob.size()
+                )));
+            }
+            blob.write(&write_req.data).await?;
+            if write_req.finish_write {
+                break;
+            }
+            write_req = stream
+                .message()
+                .await?
+                .ok_or_else(|| Status::invalid_argument("upload ended before finish_write"))?;
+        }
+        let digest = blob.finish().await?;
+        if digest != resource.digest {
+            return Err(Status::invalid_argument(format!(
+                "uploaded {}/{} does not match its digest",
+                digest.hash, digest.size_bytes
+            )));
+        }
+        info!("Bytes written: {}", digest.size_bytes);
+        Ok(Response::new(api::WriteResponse {
+            committed_size: digest.size_bytes,
+        }))
     }
 }
 
@@ -25,36 +206,64 @@ impl api::ByteStream for BytestreamService {
         &self,
         request: Request<api::ReadRequest>,
     ) -> Result<Response<Self::ReadStream>, Status> {
-        // TODO variable read offsets
-        assert_eq!(0, request.get_ref().read_offset);
-        assert_eq!(0, request.get_ref().read_limit);
-        let resource_name = &request.get_ref().resource_name;
-
-        let segments: Vec<&str> = resource_name.split("/").collect();
-        let instance = segments[0];
-        assert_eq!("blobs", segments[1]);
-        let hash = segments[2];
-        let len = segments[3];
+        let read = request.get_ref();
+        if read.read_offset < 0 || read.read_limit < 0 {
+            return Err(Status::out_of_range("negative read offset or limit"));
+        }
+        if let Some(reader) = self
+            .log_streams
+            .reader(&read.resource_name, read.read_offset as u64)?
+        {
+            info!("Following log stream");
+            return Ok(Response::new(Self::read_log(reader, read.read_limit)));
+        }
+
+        let resource = BlobResource::parse_read(&read.resource_name)?;
+        let (offset, limit) = (read.read_offset as u64, read.read_limit);
         info!("Reading");
 
-        let (tx, rx) = mpsc::channel(128);
-        let content_store = self.content_store.clone();
-        let instance = instance.clone().to_owned();
-        let hash = hash.clone().to_owned();
+        let mut file = self
+            .content_store
+            .open_blob(&resource.instance, &resource.digest.hash)
+            .await?;
+        let len = file.metadata().await.map_err(CasError::from)?.len();
+        if offset > len {
+            return Err(Status::out_of_range(format!(
+                "read offset {} past the end of a {} byte blob",
+                offset, len
+            )));
+        }
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(CasError::from)?;
+
+        let (tx, rx) = mpsc::channel(16);
         tokio::spawn(async move {
-            if let Ok(buf) = content_store.read_to_end(&instance, &hash).await {
-                info!("blob size {:?}", buf.len());
-                let op = api::ReadResponse { data: buf };
-                tx.send(Result::<_, Status>::Ok(op)).await.unwrap();
-                info!("Read.");
+            let mut remaining = if limit > 0 {
+                limit as usize
             } else {
-                info!("Did not Read!");
-                tx.send(Result::<_, Status>::Err(Status::new(
-                    tonic::Code::Unknown,
-                    "oh no",
-                )))
-                .await
-                .unwrap();
+                usize::MAX
+            };
+            while remaining > 0 {
+                let mut data = match content_storage::read_chunk(&mut file).await {
+                    Ok(data) => data,
+                    Err(e) => {
+                        info!("Did not Read: {}", e);
+                        let _ = tx.send(Err(CasError::from(e).into())).await;
+                        break;
+                    }
+                };
+                let last = data.len() < CHUNK_SIZE;
+                data.truncate(remaining);
+                remaining -= data.len();
+                if tx.send(Ok(api::ReadResponse { data })).await.is_err() {
+                    info!("Blob reader went away");
+                    break;
+                }
+                if last {
+                    info!("Read.");
+                    break;
+                }
             }
         });
         let output_stream = ReceiverStream::new(rx);
@@ -67,40 +276,16 @@ impl api::ByteStream for BytestreamService {
         request: Request<tonic::Streaming<api::WriteRequest>>,
     ) -> Result<Response<api::WriteResponse>, Status> {
         let mut stream = request.into_inner();
-        let mut size: usize = 0;
-        if let Some(write_req) = stream.message().await? {
-            info!("Name: {:?}", &write_req.resource_name);
-            let segments: Vec<&str> = write_req.resource_name.split("/").collect();
-            let instance = segments[0];
-            assert_eq!("uploads", segments[1]);
-            let uuid = uuid::Uuid::parse_str(segments[2])
-                .map_err(|_| Status::invalid_argument("not a valid uuid"))?;
-            assert_eq!("blobs", segments[3]);
-            let hash = segments[4];
-            let data_size: usize = segments[5]
-                .parse()
-                .map_err(|_| Status::invalid_argument("bad size value"))?;
-
-            info!("Writing to blob");
-            let bytes_written = self
-                .content_store
-                .write_data(
-                    instance,
-                    uuid,
-                    hash,
-                    data_size,
-                    write_req.write_offset,
-                    write_req.finish_write,
-                    &write_req.data,
-                )
-                .await
-                .map_err(|_| Status::internal("content store could not write data"))?;
-            info!("Bytes written: {}", bytes_written);
-            size += bytes_written;
+        let Some(write_req) = stream.message().await? else {
+            return Err(Status::invalid_argument("empty write"));
+        };
+        info!("Name: {:?}", &write_req.resource_name);
+        if self.log_streams.is_write_resource(&write_req.resource_name) {
+            let resource_name = write_req.resource_name.clone();
+            return self.write_log(&resource_name, write_req, &mut stream).await;
         }
-        Ok(Response::new(api::WriteResponse {
-            committed_size: size as i64,
-        }))
+        let resource = BlobResource::parse_write(&write_req.resource_name)?;
+        self.write_blob(resource, write_req, &mut stream).await
     }
 
     #[instrument(skip_all, fields(resource = _request.get_ref().resource_name))]
@@ -112,3 +297,92 @@ impl api::ByteStream for BytestreamService {
         Err(Status::not_found("BWB TODO"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const UUID: &str = "3e0c4a8e-7bd2-4f5c-9a53-2f0b0f0ab1c2";
+
+    fn blob(instance: &str, size: i64) -> BlobResource {
+        BlobResource {
+            instance: instance.to_string(),
+            digest: api::Digest {
+                hash: HASH.to_string(),
+                size_bytes: size,
+            },
+        }
+    }
+
+    #[test]
+    fn read_resource_instances() {
+        assert_eq!(
+            BlobResource::parse_read(&format!("blobs/{}/12", HASH)),
+            Ok(blob("", 12))
+        );
+        assert_eq!(
+            BlobResource::parse_read(&format!("remote-execution/blobs/{}/12", HASH)),
+            Ok(blob("remote-execution", 12))
+        );
+        assert_eq!(
+            BlobResource::parse_read(&format!("a/b/blobs/{}/0", HASH)),
+            Ok(blob("a/b", 0))
+        );
+    }
+
+    #[test]
+    fn read_resource_errors() {
+        assert!(matches!(
+            BlobResource::parse_read(&format!("uploads/{}/12", HASH)),
+            Err(ResourceError::NotBlob(_))
+        ));
+        assert!(matches!(
+            BlobResource::parse_read("blobs"),
+            Err(ResourceError::NotBlob(_))
+        ));
+        assert!(matches!(
+            BlobResource::parse_read("blobs/../12"),
+            Err(ResourceError::Hash(_))
+        ));
+        assert!(matches!(
+            BlobResource::parse_read(&format!("blobs/{}/-1", HASH)),
+            Err(ResourceError::Size(_))
+        ));
+    }
+
+    #[test]
+    fn write_resource_instances() {
+        assert_eq!(
+            BlobResource::parse_write(&format!("uploads/{}/blobs/{}/12", UUID, HASH)),
+            Ok(blob("", 12))
+        );
+        assert_eq!(
+            BlobResource::parse_write(&format!("a/b/uploads/{}/blobs/{}/12", UUID, HASH)),
+            Ok(blob("a/b", 12))
+        );
+        assert_eq!(
+            BlobResource::parse_write(&format!(
+                "i/uploads/{}/blobs/{}/12/uploads/meta",
+                UUID, HASH
+            )),
+            Ok(blob("i", 12))
+        );
+    }
+
+    #[test]
+    fn write_resource_errors() {
+        assert!(matches!(
+            BlobResource::parse_write(&format!("blobs/{}/12", HASH)),
+            Err(ResourceError::NotUpload(_))
+        ));
+        assert!(matches!(
+            BlobResource::parse_write(&format!("uploads/x/blobs/{}/12", HASH)),
+            Err(ResourceError::Uuid(_))
+        ));
+        assert!(matches!(
+            BlobResource::parse_write(&format!("uploads/{}/blobs/{}", UUID, HASH)),
+            Err(ResourceError::NotUpload(_))
+        ));
+    }
+}
@@ -1,11 +1,29 @@
-use crate::{api, content_storage::ContentStorage, execution_runner::ExecutionRunner};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use crate::api;
+use crate::platform::PlatformConfig;
+use std::ops::RangeInclusive;
 use tonic::{Request, Response, Status};
 use tracing::{info, instrument};
 
-#[derive(Debug, Default)]
-pub struct CapabilitiesService {}
+#[derive(Debug)]
+pub struct CapabilitiesService {
+    execution_priorities: RangeInclusive<i32>,
+    absolute_symlinks: bool,
+    platform_properties: Vec<String>,
+}
+
+impl CapabilitiesService {
+    pub fn new(
+        execution_priorities: RangeInclusive<i32>,
+        absolute_symlinks: bool,
+        platform: &PlatformConfig,
+    ) -> Self {
+        CapabilitiesService {
+            execution_priorities,
+            absolute_symlinks,
+            platform_properties: platform.names().cloned().collect(),
+        }
+    }
+}
 
 #[tonic::async_trait]
 impl api::Capabilities for CapabilitiesService {
@@ -15,9 +33,9 @@ impl api::Capabilities for CapabilitiesService {
         request: Request<api::GetCapabilitiesRequest>,
     ) -> Result<Response<api::ServerCapabilities>, Status> {
         info!("Instance: {}", request.get_ref().instance_name);
-        let api_version = api::SemVer {
+        let version = |minor| api::SemVer {
             major: 2,
-            minor: 0,
+            minor,
             patch: 0,
             prerelease: String::default(),
         };
@@ -29,7 +47,11 @@ impl api::Capabilities for CapabilitiesService {
             }),
             cache_priority_capabilities: None,
             max_batch_total_size_bytes: 0,
-            symlink_absolute_path_strategy: 0,
+            symlink_absolute_path_strategy: if self.absolute_symlinks {
+                api::symlink_absolute_path_strategy::Value::Allowed.into()
+            } else {
+                api::symlink_absolute_path_strategy::Value::Disallowed.into()
+            },
             supported_compressors: vec![],
             supported_batch_update_compressors: vec![],
         };
@@ -37,16 +59,23 @@ impl api::Capabilities for CapabilitiesService {
         let exec_caps = api::ExecutionCapabilities {
             digest_function: api::digest_function::Value::Sha256.into(),
             exec_enabled: true,
-            execution_priority_capabilities: None,
-            supported_node_properties: vec![],
+            execution_priority_capabilities: Some(api::PriorityCapabilities {
+                priorities: vec![api::priority_capabilities::PriorityRange {
+                    min_priority: *self.execution_priorities.start(),
+                    max_priority: *self.execution_priorities.end(),
+                }],
+            }),
+            // Lets clients find out which platform properties they may set
+            supported_node_properties: self.platform_properties.clone(),
         };
 
         let caps = api::ServerCapabilities {
             cache_capabilities: Some(cache_capabilities),
             execution_capabilities: Some(exec_caps),
             deprecated_api_version: None,
-            low_api_version: Some(api_version.clone()),
-            high_api_version: Some(api_version.clone()),
+            low_api_version: Some(version(0)),
+            // Output paths come from v2.1, platform properties on the action from v2.2
+            high_api_version: Some(version(2)),
         };
         Ok(Response::new(caps))
     }
@@ -1,5 +1,4 @@
-use crate::{api, content_storage::ContentStorage, execution_runner::ExecutionRunner};
-use tokio::sync::mpsc;
+use crate::api;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 use tracing::{info, instrument};
@@ -1,123 +1,468 @@
 use crate::{
     api,
+    config::{Config, MountConfig, ToolchainConfig},
     content_storage::{CasError, ContentStorage},
-    execution_runner::{ActionError, ExecutionRunner, Stage},
-    sandboxed_action::{Mapping, SandboxedAction, SandboxedActionResp},
+    execution_runner::{ActionError, ExecutionRunner, Progress},
+    image::{ImageError, ImageStore},
+    log_stream::LogStreams,
+    operation_registry::Watcher,
+    platform::{self, Platform, PlatformConfig},
+    sandboxed_action::{
+        check_parents, resolve_in_root, Mapping, Output, SandboxDir, SandboxedAction,
+        SandboxedActionResp, Symlink,
+    },
+    scheduler::Scheduler,
+    util::hostname,
+    verify::Verifier,
 };
 use futures::future::BoxFuture;
 use prost::Message;
-use std::io::Read;
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::{collections::HashMap, os::fd::FromRawFd};
-use tokio::{io::AsyncReadExt, sync::mpsc};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_stream::StreamExt;
 use tonic::{Request, Response, Status};
 use tracing::{info, instrument};
 
+type ActionFuture = BoxFuture<'static, Result<api::ExecuteResponse, ActionError>>;
+
+/// Execution time limits applied to actions.
+#[derive(Clone, Copy, Debug)]
+pub struct ActionTimeouts {
+    /// Used when the action does not ask for a timeout.
+    pub default: Duration,
+    /// Actions asking for more than this get this much.
+    pub max: Duration,
+}
+
+impl ActionTimeouts {
+    fn for_action(&self, action: &api::Action) -> Result<Duration, ActionError> {
+        let requested = match &action.timeout {
+            Some(timeout) => Duration::try_from(timeout.clone())
+                .map_err(|_| ActionError::InvalidAction("negative timeout".to_string()))?,
+            None => Duration::ZERO,
+        };
+        if requested.is_zero() {
+            return Ok(self.default);
+        }
+        if requested > self.max {
+            // A long timeout only asks the server for its longest one
+            info!(
+                "Timeout of {:?} lowered to the maximum of {:?}",
+                requested, self.max
+            );
+            return Ok(self.max);
+        }
+        Ok(requested)
+    }
+}
+
+/// How the sandbox of every action is laid out.
+#[derive(Clone, Debug)]
+pub struct SandboxConfig {
+    /// Whether input symlinks may point at absolute paths.
+    pub absolute_symlinks: bool,
+    pub platform: PlatformConfig,
+    /// Host paths mounted into every sandbox.
+    pub mounts: Vec<MountConfig>,
+    /// Extra mounts picked through the `toolchain` platform property.
+    pub toolchains: BTreeMap<String, ToolchainConfig>,
+    /// Images picked through the `container-image` platform property, if any are configured.
+    pub images: Option<ImageStore>,
+}
+
+impl SandboxConfig {
+    /// Sandboxes as `config` lays them out, images are unpacked below `image_cache`.
+    pub fn new(
+        config: &Config,
+        absolute_symlinks: bool,
+        image_cache: PathBuf,
+    ) -> std::io::Result<Self> {
+        let images = match &config.images {
+            Some(images) => Some(ImageStore::new(images.dir.clone(), image_cache)?),
+            None => None,
+        };
+        Ok(SandboxConfig {
+            absolute_symlinks,
+            platform: PlatformConfig::new(config.platform.clone()),
+            mounts: config.mounts.clone(),
+            toolchains: config.toolchains.clone(),
+            images,
+        })
+    }
+
+    /// Host mounts for an action running on `platform`, an image replaces the base mounts.
+    fn mounts(&self, platform: &Platform) -> impl Iterator<Item = &MountConfig> {
+        let toolchain = platform
+            .get(platform::TOOLCHAIN)
+            .and_then(|name| self.toolchains.get(name));
+        let base = match platform.get(platform::CONTAINER_IMAGE) {
+            Some(_) => &[][..],
+            None => &self.mounts[..],
+        };
+        base.iter().chain(
+            toolchain
+                .into_iter()
+                .flat_map(|toolchain| &toolchain.mounts),
+        )
+    }
+}
+
+/// Where actions run.
+#[derive(Clone)]
+pub enum Executor {
+    /// On this host, every action in a directory of its own below `sandbox_root`.
+    Local {
+        sandbox_root: PathBuf,
+        sandbox: SandboxConfig,
+    },
+    /// On `gaudi worker` processes leasing them through the Remote Workers API.
+    Remote(Scheduler),
+}
+
 pub struct ExecutionService {
     cas: ContentStorage,
-    sandbox_root: PathBuf,
+    /// Where the stdout and stderr of running actions can be followed.
+    log_streams: LogStreams,
     exec_runner: ExecutionRunner,
+    timeouts: ActionTimeouts,
+    executor: Executor,
+    verifier: Verifier,
+    /// Properties the server advertises, actions asking for others are rejected. Their defaults
+    /// decide which actions are verified.
+    platform: PlatformConfig,
+    /// Reported as the worker in the execution metadata of every local result.
+    worker: String,
 }
 
 impl ExecutionService {
-    pub fn new(cas: ContentStorage, sandbox_root: PathBuf, exec_runner: ExecutionRunner) -> Self {
+    pub fn new(
+        cas: ContentStorage,
+        log_streams: LogStreams,
+        exec_runner: ExecutionRunner,
+        timeouts: ActionTimeouts,
+        executor: Executor,
+        verifier: Verifier,
+        platform: PlatformConfig,
+    ) -> Self {
         ExecutionService {
             cas,
-            sandbox_root,
+            log_streams,
             exec_runner,
+            timeouts,
+            executor,
+            verifier,
+            platform,
+            worker: hostname(),
         }
     }
 }
 
+fn now() -> Option<prost_types::Timestamp> {
+    Some(SystemTime::now().into())
+}
+
+/// Fetch an input proto, recording it as missing instead of failing when it is not stored.
+async fn fetch_input<T: prost::Message + Default>(
+    cas: &ContentStorage,
+    digest: &api::Digest,
+    missing: &mut Vec<api::Digest>,
+) -> Result<Option<T>, ActionError> {
+    match cas.get_proto("remote-execution", digest).await {
+        Ok(proto) => Ok(Some(proto)),
+        Err(CasError::MissingBlob(_)) => {
+            missing.push(digest.clone());
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Everything needed to lay out the input root in the sandbox.
+#[derive(Default)]
+struct Inputs {
+    files: Vec<Mapping>,
+    symlinks: Vec<Symlink>,
+    /// Blobs of the tree that are not in the CAS.
+    missing: Vec<api::Digest>,
+}
+
+/// Walk the whole input tree, mapping every file into the sandbox and collecting the digests of
+/// all blobs that are not in the CAS.
 #[instrument(skip_all)]
 fn create_mapping<'a>(
     cas: &'a ContentStorage,
     dir: api::Directory,
     root: PathBuf,
-    mapping: &'a mut Vec<Mapping>,
-) -> BoxFuture<'a, Result<(), CasError>> {
+    absolute_symlinks: bool,
+    inputs: &'a mut Inputs,
+) -> BoxFuture<'a, Result<(), ActionError>> {
     Box::pin(async move {
-        assert_eq!(dir.symlinks.len(), 0);
+        for symlink in dir.symlinks {
+            let path = root.join(&symlink.name);
+            let target = PathBuf::from(symlink.target);
+            if target.as_os_str().is_empty() {
+                return Err(ActionError::InvalidAction(format!(
+                    "symlink {} has no target",
+                    path.display()
+                )));
+            }
+            if target.is_absolute() && !absolute_symlinks {
+                return Err(ActionError::InvalidAction(format!(
+                    "symlink {} has an absolute target {}",
+                    path.display(),
+                    target.display()
+                )));
+            }
+            inputs.symlinks.push(Symlink { path, target });
+        }
         for file in dir.files {
             let mut dest_path = root.clone();
             dest_path.push(&file.name);
 
-            let mut source_path = cas.get_root_path().to_path_buf();
-            source_path.push("remote-execution");
-            source_path.push(file.digest.expect("must have a digest").hash);
-
-            mapping.push(Mapping {
+            let digest = file.digest.ok_or_else(|| {
+                ActionError::InvalidAction(format!("{} has no digest", dest_path.display()))
+            })?;
+            if !cas.has_blob("remote-execution", &digest).await? {
+                inputs.missing.push(digest);
+                continue;
+            }
+            let source_path = if file.is_executable {
+                cas.get_executable_path("remote-execution", &digest).await?
+            } else {
+                let mut source_path = cas.get_root_path().to_path_buf();
+                source_path.push("remote-execution");
+                source_path.push(digest.hash);
+                source_path
+            };
+
+            inputs.files.push(Mapping {
                 dest_path,
                 source_path,
             });
         }
         for directory_node in &dir.directories {
-            let dir: api::Directory = cas
-                .get_proto("remote-execution", directory_node.digest.as_ref().unwrap())
-                .await?;
             let mut new_root = root.clone();
             new_root.push(&directory_node.name);
-            create_mapping(cas, dir, new_root, mapping).await?;
+            let digest = directory_node.digest.as_ref().ok_or_else(|| {
+                ActionError::InvalidAction(format!("{} has no digest", new_root.display()))
+            })?;
+            if let Some(dir) = fetch_input(cas, digest, &mut inputs.missing).await? {
+                create_mapping(cas, dir, new_root, absolute_symlinks, inputs).await?;
+            }
         }
         Ok(())
     })
 }
 
-async fn run_action(
-    cas: ContentStorage,
+/// The parts of an Action needed to run it.
+#[derive(Clone)]
+pub struct ActionRequest {
     command_digest: api::Digest,
     root_digest: api::Digest,
-) -> Result<SandboxedActionResp, ActionError> {
-    let cmd: api::Command = cas.get_proto("remote-execution", &command_digest).await?;
-    let root: api::Directory = cas.get_proto("remote-execution", &root_digest).await?;
+    platform: Option<api::Platform>,
+    timeout: Duration,
+    /// Log stream files the action's output is appended to.
+    stdout: PathBuf,
+    stderr: PathBuf,
+}
+
+impl ActionRequest {
+    /// Check that the action names its command and input root. Its output is discarded unless
+    /// [`ActionRequest::logs`] says where it goes.
+    pub fn new(action: &api::Action, timeout: Duration) -> Result<Self, ActionError> {
+        let command_digest = action
+            .command_digest
+            .clone()
+            .ok_or_else(|| ActionError::InvalidAction(String::from("no command digest")))?;
+        let root_digest = action
+            .input_root_digest
+            .clone()
+            .ok_or_else(|| ActionError::InvalidAction(String::from("no root digest")))?;
+        Ok(ActionRequest {
+            command_digest,
+            root_digest,
+            platform: action.platform.clone(),
+            timeout,
+            stdout: PathBuf::new(),
+            stderr: PathBuf::new(),
+        })
+    }
+
+    pub fn logs(mut self, stdout: &Path, stderr: &Path) -> Self {
+        self.stdout = stdout.to_path_buf();
+        self.stderr = stderr.to_path_buf();
+        self
+    }
+
+    pub fn command_digest(&self) -> &api::Digest {
+        &self.command_digest
+    }
 
-    if !cmd.output_paths.is_empty() {
-        panic!("output paths is set but we only support v2.0 for now");
+    pub fn root_digest(&self) -> &api::Digest {
+        &self.root_digest
     }
+}
+
+/// Run an action in a fresh sandbox below `sandbox_root` and store its outputs in `cas`.
+pub async fn execute_locally(
+    cas: ContentStorage,
+    sandbox_root: &Path,
+    sandbox: &SandboxConfig,
+    request: ActionRequest,
+    mut metadata: api::ExecutedActionMetadata,
+) -> Result<api::ExecuteResponse, ActionError> {
+    // Only called once the action left the queue
+    metadata.worker_start_timestamp = now();
+    // Declared first so it outlives the sandbox process when the action is cancelled
+    let sandbox_dir = SandboxDir::create(sandbox_root)?;
+    let resp = run_action(
+        cas.clone(),
+        request,
+        sandbox,
+        sandbox_dir.path(),
+        &mut metadata,
+    )
+    .await?;
+    info!("Completed: {:?}", resp);
+    create_result(cas, sandbox_dir.path(), resp, metadata).await
+}
+
+async fn run_action(
+    cas: ContentStorage,
+    request: ActionRequest,
+    sandbox: &SandboxConfig,
+    sandbox_dir: &Path,
+    metadata: &mut api::ExecutedActionMetadata,
+) -> Result<SandboxedActionResp, ActionError> {
+    metadata.input_fetch_start_timestamp = now();
+    // The whole input tree is checked up front so every missing blob is reported at once
+    let mut inputs = Inputs::default();
+    let cmd: Option<api::Command> =
+        fetch_input(&cas, &request.command_digest, &mut inputs.missing).await?;
+    if let Some(root) = fetch_input(&cas, &request.root_digest, &mut inputs.missing).await? {
+        info!("Root: {:#?}", root);
+        create_mapping(
+            &cas,
+            root,
+            sandbox_dir.to_path_buf(),
+            sandbox.absolute_symlinks,
+            &mut inputs,
+        )
+        .await?;
+    }
+    let cmd = match cmd {
+        Some(cmd) if inputs.missing.is_empty() => cmd,
+        _ => {
+            let mut missing = inputs.missing;
+            missing.sort_by(|a, b| a.hash.cmp(&b.hash));
+            missing.dedup();
+            return Err(ActionError::MissingInputs(missing));
+        }
+    };
+    let platform = sandbox
+        .platform
+        .resolve(request.platform.as_ref(), cmd.platform.as_ref())?;
+    info!("Platform: {:?}", platform);
+    let rootfs = match platform.get(platform::CONTAINER_IMAGE) {
+        Some(image) => Some(
+            sandbox
+                .images
+                .as_ref()
+                .ok_or_else(|| ImageError::NotFound(image.to_string()))?
+                .rootfs(image)
+                .await?,
+        ),
+        None => None,
+    };
+
+    let program = cmd
+        .arguments
+        .first()
+        .ok_or_else(|| ActionError::InvalidCommand("no arguments".to_string()))?;
 
     info!("Command: {:#?}", cmd);
-    info!("Root: {:#?}", root);
     let env_vars: Vec<(String, String)> = cmd
         .environment_variables
         .iter()
         .map(|ev| (ev.name.clone(), ev.value.clone()))
         .collect();
+    metadata.input_fetch_completed_timestamp = now();
+
+    let working_directory =
+        resolve_in_root(Path::new(&cmd.working_directory)).ok_or_else(|| {
+            ActionError::InvalidCommand(format!(
+                "working directory {} is outside of the input root",
+                cmd.working_directory
+            ))
+        })?;
+    // REv2.1 clients list every output in output_paths and the older fields are ignored
+    let (output_files, output_directories, output_paths) = if cmd.output_paths.is_empty() {
+        (&cmd.output_files[..], &cmd.output_directories[..], &[][..])
+    } else {
+        (&[][..], &[][..], &cmd.output_paths[..])
+    };
+    for output in output_files
+        .iter()
+        .chain(output_directories)
+        .chain(output_paths)
+    {
+        let resolved = resolve_in_root(&working_directory.join(output));
+        if resolved.is_none_or(|path| path.as_os_str().is_empty()) {
+            return Err(ActionError::InvalidCommand(format!(
+                "output {} is outside of the input root",
+                output
+            )));
+        }
+    }
 
-    let mut mappings = vec![];
-    create_mapping(
-        &cas,
-        root,
-        PathBuf::from("/home/ben/workspace/gaudi/sandbox"),
-        &mut mappings,
-    )
-    .await?;
-
-    let mut action = SandboxedAction::new(&cmd.arguments[0])
+    let mut action = SandboxedAction::new(program, sandbox_dir);
+    if let Some(rootfs) = &rootfs {
+        action = action.root_filesystem(rootfs)?;
+    }
+    for mount in sandbox.mounts(&platform) {
+        if !mount.source.exists() {
+            if mount.optional {
+                continue;
+            }
+            return Err(ActionError::SandboxIoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("host mount {} does not exist", mount.source.display()),
+            )));
+        }
+        action = action.mount(&mount.source, mount.target(), mount.readonly);
+    }
+    let mut action = action
+        .stdout(&request.stdout)
+        .stderr(&request.stderr)
         .args(&cmd.arguments[..])
         .envs(&env_vars)
-        .input_file_mapping(&mappings)
-        .input_file("/usr/bin/")
-        .input_file("/usr/lib/")
-        .input_file("/usr/lib64/")
-        .input_file("/usr/include/")
-        .input_file("/usr/local/include/")
-        .input_file("/lib64/ld-linux-x86-64.so.2")
-        .input_file("/lib/gcc/x86_64-pc-linux-gnu/")
-        .input_file("/usr/include/c++/12.2.0/")
-        .input_file("/usr/local/include")
-        .input_file("/usr/include/")
-        .output_files(
-            &cmd.output_files
-                .iter()
-                .map(PathBuf::from)
-                .collect::<Vec<PathBuf>>(),
-        );
+        .input_file_mapping(&inputs.files)
+        .input_symlinks(&inputs.symlinks)
+        .working_directory(&working_directory)
+        .network(platform.get(platform::NETWORK) == Some("on"))
+        .output_files(output_files)?
+        .output_directories(output_directories)?
+        .output_paths(output_paths)?;
 
     info!("Running action...");
-    let spawned_action = action.spawn()?;
-    spawned_action.status().await.map_err(Into::into)
+    metadata.execution_start_timestamp = now();
+    let spawned_action = action.spawn().await?;
+    let timeout = request.timeout;
+    let resp = match tokio::time::timeout(timeout, spawned_action.status()).await {
+        Ok(resp) => resp?,
+        Err(_) => {
+            info!("Action exceeded its timeout of {:?}, killing it", timeout);
+            spawned_action.kill()?;
+            let mut resp = spawned_action.status().await?;
+            resp.timed_out = true;
+            resp
+        }
+    };
+    metadata.execution_completed_timestamp = now();
+    Ok(resp)
 }
 
 #[tonic::async_trait]
@@ -136,179 +481,538 @@ impl api::Execution for ExecutionService {
             .ok_or(Status::invalid_argument("no action digest"))?;
 
         let instance = request.instance_name;
+        let priority = request
+            .execution_policy
+            .map(|policy| policy.priority)
+            .unwrap_or_default();
 
         let action: api::Action = self
             .cas
             .get_proto(&instance, &action_digest)
             .await
-            .map_err(|_| Status::invalid_argument("bad action proto"))?;
+            .map_err(|e| match e {
+                CasError::MissingBlob(_) => ActionError::MissingInputs(vec![action_digest.clone()]),
+                e => ActionError::from(e),
+            })
+            .map_err(Status::from)?;
 
         info!("Action: {:?}", action);
+        let timeout = self.timeouts.for_action(&action).map_err(Status::from)?;
 
-        let command_digest = action.command_digest.ok_or(Status::invalid_argument(
-            "Invalid Action: no command digest",
-        ))?;
-        let root_digest = action
-            .input_root_digest
-            .ok_or(Status::invalid_argument("Invalid Action: no root digest"))?;
-
-        info!("command digest: {:?}", command_digest);
-
-        let action_fut = Box::pin(run_action(self.cas.clone(), command_digest, root_digest));
+        let request = ActionRequest::new(&action, timeout).map_err(Status::from)?;
+        info!("command digest: {:?}", request.command_digest);
 
-        let (tx, rx) = mpsc::channel(128);
-        let (uuid, mut action_stream) = self.exec_runner.queue(action_fut);
+        // Only what the action asks for, whoever runs it applies their own defaults. A command
+        // that is missing is reported along with the rest of the inputs once the action runs.
+        let command: Option<api::Command> = self
+            .cas
+            .get_proto(&instance, &request.command_digest)
+            .await
+            .ok();
+        let platform = Platform::requested(
+            action.platform.as_ref(),
+            command.and_then(|command| command.platform).as_ref(),
+        )
+        .map_err(|e| Status::from(ActionError::from(e)))?;
+        // Rejected before it waits in line, no worker would ever run it either
+        self.platform
+            .check(&platform)
+            .map_err(|e| Status::from(ActionError::from(e)))?;
+        let verifier = self
+            .verifier
+            .wanted(&self.platform.with_defaults(platform.clone()))
+            .then(|| {
+                info!("Verifying the action is deterministic");
+                (
+                    self.verifier.clone(),
+                    self.cas.clone(),
+                    action_digest.clone(),
+                )
+            });
 
-        let cas = self.cas.clone();
-        let sandbox_root = self.sandbox_root.clone();
-        tokio::spawn(async move {
-            while let Some(stage) = action_stream.next().await {
-                info!("=============== STAGE : {:?} =============== ", stage);
-                let op = match stage {
-                    Stage::Completed(resp) => {
-                        info!("Completed: {:?}", resp);
-                        let (metadata, result) = create_result(
+        let stdout = self.log_streams.open(&instance)?;
+        let stderr = self.log_streams.open(&instance)?;
+        let stream_names = (stdout.name().to_string(), stderr.name().to_string());
+        let queued_timestamp = now();
+        let action_fn: Box<dyn FnOnce(Progress) -> ActionFuture + Send> = match &self.executor {
+            Executor::Local {
+                sandbox_root,
+                sandbox,
+            } => {
+                // The second run of a verified action logs to streams nobody follows
+                let second = match verifier {
+                    Some(_) => {
+                        let (stdout, stderr) = (
+                            self.log_streams.open(&instance)?,
+                            self.log_streams.open(&instance)?,
+                        );
+                        let request = request.clone().logs(stdout.path(), stderr.path());
+                        Some((request, stdout, stderr))
+                    }
+                    None => None,
+                };
+                let request = request.logs(stdout.path(), stderr.path());
+                let cas = self.cas.clone();
+                let sandbox_root = sandbox_root.clone();
+                let sandbox = sandbox.clone();
+                let metadata = api::ExecutedActionMetadata {
+                    worker: self.worker.clone(),
+                    queued_timestamp,
+                    ..Default::default()
+                };
+                Box::new(move |progress: Progress| {
+                    Box::pin(async move {
+                        progress.set_stage(api::execution_stage::Value::Executing);
+                        let result = execute_locally(
                             cas.clone(),
-                            sandbox_root.clone(),
-                            action_digest.clone(),
-                            resp,
+                            &sandbox_root,
+                            &sandbox,
+                            request,
+                            metadata.clone(),
                         )
-                        .await?;
-                        api::Operation {
-                            name: uuid.to_string(),
-                            done: true,
-                            metadata,
-                            result,
+                        .await;
+                        // Held until here, readers following the logs stop once they are finalized
+                        drop((stdout, stderr));
+                        let mut response = result?;
+                        if let (
+                            Some((verifier, cas, action_digest)),
+                            Some((second, stdout, stderr)),
+                        ) = (verifier, second)
+                        {
+                            let second = execute_locally(
+                                cas.clone(),
+                                &sandbox_root,
+                                &sandbox,
+                                second,
+                                metadata,
+                            )
+                            .await;
+                            drop((stdout, stderr));
+                            verifier
+                                .verify(&cas, &action_digest, &mut response, second)
+                                .await;
                         }
-                    }
-                    _ => api::Operation {
-                        name: uuid.to_string(),
-                        done: false,
-                        metadata: None,
-                        result: None,
-                    },
+                        Ok(response)
+                    }) as ActionFuture
+                })
+            }
+            Executor::Remote(scheduler) => {
+                let assignment = api::Assignment {
+                    instance_name: instance.clone(),
+                    action: Some(api::Action {
+                        timeout: prost_types::Duration::try_from(timeout).ok(),
+                        ..action
+                    }),
+                    stdout_stream: stdout.write_resource_name().to_string(),
+                    stderr_stream: stderr.write_resource_name().to_string(),
+                    action_digest: Some(action_digest.clone()),
                 };
-                info!("Operation: {:?}", op);
-                tx.send(Result::<_, Status>::Ok(op)).await.unwrap();
+                let second = verifier.as_ref().map(|_| api::Assignment {
+                    stdout_stream: String::new(),
+                    stderr_stream: String::new(),
+                    ..assignment.clone()
+                });
+                let scheduler = scheduler.clone();
+                Box::new(move |progress: Progress| {
+                    Box::pin(async move {
+                        let on_lease = |leased| {
+                            progress.set_stage(match leased {
+                                true => api::execution_stage::Value::Executing,
+                                false => api::execution_stage::Value::Queued,
+                            })
+                        };
+                        let mut response = scheduler
+                            .run(assignment, priority, platform.clone(), on_lease)
+                            .await;
+                        // Only the scheduler knows how long the action waited for a worker
+                        if let Some(metadata) = response
+                            .result
+                            .as_mut()
+                            .and_then(|result| result.execution_metadata.as_mut())
+                        {
+                            metadata.queued_timestamp = queued_timestamp;
+                        }
+                        // The worker finishes the streams, unless it went away first
+                        drop((stdout, stderr));
+                        if let (Some((verifier, cas, action_digest)), Some(second)) =
+                            (verifier, second)
+                        {
+                            let second = scheduler.run(second, priority, platform, |_| {}).await;
+                            verifier
+                                .verify(&cas, &action_digest, &mut response, Ok(second))
+                                .await;
+                        }
+                        Ok(response)
+                    }) as ActionFuture
+                })
             }
-            Ok::<(), CasError>(())
-        });
-
-        let output_stream = ReceiverStream::new(rx);
-        Ok(Response::new(output_stream as Self::ExecuteStream))
+        };
+
+        let name = self
+            .exec_runner
+            .queue(action_digest, priority, action_fn)
+            .map_err(|e| Status::resource_exhausted(e.to_string()))?;
+        self.exec_runner
+            .operations()
+            .set_log_streams(&name, &stream_names.0, &stream_names.1);
+        let watcher = self
+            .exec_runner
+            .operations()
+            .attach(&name)
+            .ok_or_else(|| Status::internal("operation vanished before it was watched"))?;
+
+        Ok(Response::new(stream_operation(watcher)))
     }
 
     type WaitExecutionStream = ReceiverStream<Result<api::longrunning::Operation, Status>>;
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(name = request.get_ref().name))]
     async fn wait_execution(
         &self,
         request: Request<api::WaitExecutionRequest>,
     ) -> Result<Response<Self::WaitExecutionStream>, Status> {
-        info!("{:?}", request);
-        todo!()
+        let name = &request.get_ref().name;
+        let watcher = self
+            .exec_runner
+            .operations()
+            .attach(name)
+            .ok_or_else(|| Status::not_found(format!("unknown operation: {}", name)))?;
+        info!("Reattached.");
+        Ok(Response::new(stream_operation(watcher)))
     }
 }
 
-pub type ActionResult = (Option<prost_types::Any>, Option<api::operation::Result>);
+/// Forward every stage change of an operation to a client stream, ending once it is done.
+///
+/// The watcher is held until the client goes away so abandoned operations get cancelled.
+fn stream_operation(
+    mut watcher: Watcher,
+) -> ReceiverStream<Result<api::longrunning::Operation, Status>> {
+    let (tx, rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        loop {
+            let state = watcher.updates.borrow_and_update().clone();
+            let op = state.to_operation();
+            info!("Operation: {:?}", op);
+            if tx.send(Ok(op)).await.is_err() || state.done() {
+                break;
+            }
+            tokio::select! {
+                changed = watcher.updates.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = tx.closed() => {
+                    info!("Client stopped watching {}", state.name);
+                    break;
+                }
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Store every file below `path` in the CAS and describe the directory as a [`api::Tree`].
+async fn upload_tree(cas: &ContentStorage, path: &Path) -> Result<api::Tree, ActionError> {
+    let mut children = vec![];
+    let root = cas
+        .add_directory("remote-execution", path.to_path_buf(), &mut children)
+        .await?;
+    // Subdirectories with the same contents only need to be listed once
+    children.sort_by_key(|(digest, _): &(api::Digest, api::Directory)| digest.hash.clone());
+    children.dedup_by(|a, b| a.0 == b.0);
+    Ok(api::Tree {
+        root: Some(root),
+        children: children.into_iter().map(|(_, dir)| dir).collect(),
+    })
+}
+
+/// What the client declared an output to be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputKind {
+    File,
+    Directory,
+    /// REv2.1 output paths, typed by whatever the action left behind.
+    Path,
+}
+
+/// Upload one output and add it to the result.
+async fn collect_output(
+    cas: &ContentStorage,
+    sandbox_path: &Path,
+    output: &Output,
+    kind: OutputKind,
+    result: &mut api::ActionResult,
+) -> Result<(), ActionError> {
+    let path = output.name.clone();
+    let source_path = &output.path;
+    // The action may have swapped a parent directory for a symlink
+    check_parents(sandbox_path, source_path)
+        .map_err(|e| ActionError::InvalidCommand(format!("output {}: {}", path, e)))?;
+    // Outputs the action did not create are simply left out
+    let meta = match tokio::fs::symlink_metadata(source_path).await {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if meta.is_symlink() {
+        let symlink = api::OutputSymlink {
+            path,
+            target: tokio::fs::read_link(source_path)
+                .await?
+                .to_string_lossy()
+                .into_owned(),
+            node_properties: None,
+        };
+        match kind {
+            OutputKind::File => result.output_file_symlinks.push(symlink),
+            OutputKind::Directory => result.output_directory_symlinks.push(symlink),
+            OutputKind::Path => {
+                // Also where v2.0 clients look, by what the link points at. A dangling link
+                // can only have been meant as a file.
+                let to_dir = tokio::fs::metadata(source_path)
+                    .await
+                    .is_ok_and(|meta| meta.is_dir());
+                match to_dir {
+                    true => result.output_directory_symlinks.push(symlink.clone()),
+                    false => result.output_file_symlinks.push(symlink.clone()),
+                }
+                result.output_symlinks.push(symlink);
+            }
+        }
+    } else if meta.is_dir() {
+        if kind == OutputKind::File {
+            return Err(ActionError::InvalidCommand(format!(
+                "output file {} is a directory",
+                path
+            )));
+        }
+        result
+            .output_directories
+            .push(upload_output_directory(cas, path, source_path).await?);
+    } else if meta.is_file() {
+        if kind == OutputKind::Directory {
+            return Err(ActionError::InvalidCommand(format!(
+                "output directory {} is not a directory",
+                path
+            )));
+        }
+        result
+            .output_files
+            .push(upload_file(cas, path, source_path, is_executable(&meta)).await?);
+    } else {
+        // Reading a FIFO or device could block forever or never end
+        return Err(ActionError::InvalidCommand(format!(
+            "output {} is not a regular file, directory or symlink",
+            path
+        )));
+    }
+    Ok(())
+}
 
 async fn create_result(
     cas: ContentStorage,
-    sandbox_path: PathBuf,
-    action_digest: api::Digest,
+    sandbox_path: &Path,
     resp: SandboxedActionResp,
-) -> Result<ActionResult, CasError> {
-    let mut output_files = vec![];
-
-    for mapping in &resp.output_paths {
-        dbg!(&mapping);
-        if mapping.source_path.is_file() {
-            let digest = cas
-                .add_new_blob_from_file("remote-execution", &mapping.source_path)
-                .await?;
-            let path = mapping.dest_path.strip_prefix(&sandbox_path).unwrap();
-            output_files.push(api::OutputFile {
-                path: path.to_str().unwrap().to_string(),
-                digest: Some(digest),
-                is_executable: false,
-                contents: vec![],
-                node_properties: None,
-            })
-        } else {
-            todo!()
-        }
+    mut metadata: api::ExecutedActionMetadata,
+) -> Result<api::ExecuteResponse, ActionError> {
+    metadata.output_upload_start_timestamp = now();
+    let mut result = api::ActionResult::default();
+
+    let outputs = resp
+        .output_files
+        .iter()
+        .map(|path| (path, OutputKind::File))
+        .chain(
+            resp.output_directories
+                .iter()
+                .map(|path| (path, OutputKind::Directory)),
+        )
+        .chain(
+            resp.output_paths
+                .iter()
+                .map(|path| (path, OutputKind::Path)),
+        );
+    for (output, kind) in outputs {
+        collect_output(&cas, sandbox_path, output, kind, &mut result).await?;
     }
-    let stderr_digest = cas
-        .add_new_blob_from_file("remote-execution", &resp.stderr)
+    result.stderr_digest = Some(
+        cas.add_new_blob_from_file("remote-execution", &resp.stderr)
+            .await?,
+    );
+    result.stdout_digest = Some(
+        cas.add_new_blob_from_file("remote-execution", &resp.stdout)
+            .await?,
+    );
+    info!("{:#?}", result.output_files);
+    metadata.output_upload_completed_timestamp = now();
+    metadata.worker_completed_timestamp = now();
+    result.exit_code = resp.status_code;
+    result.execution_metadata = Some(metadata);
+    Ok(format_result(result, resp.timed_out))
+}
+
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    meta.permissions().mode() & 0o111 != 0
+}
+
+async fn upload_file(
+    cas: &ContentStorage,
+    path: String,
+    source_path: &Path,
+    is_executable: bool,
+) -> Result<api::OutputFile, ActionError> {
+    let digest = cas
+        .add_new_blob_from_file("remote-execution", source_path)
         .await?;
-    let stdout_digest = cas
-        .add_new_blob_from_file("remote-execution", &resp.stdout)
+    Ok(api::OutputFile {
+        path,
+        digest: Some(digest),
+        is_executable,
+        contents: vec![],
+        node_properties: None,
+    })
+}
+
+async fn upload_output_directory(
+    cas: &ContentStorage,
+    path: String,
+    source_path: &Path,
+) -> Result<api::OutputDirectory, ActionError> {
+    let tree = upload_tree(cas, source_path).await.map_err(|e| match e {
+        // Named as the client knows it rather than by its place in the sandbox
+        ActionError::CasError(CasError::UnsupportedFile(file)) => {
+            let inside = file.strip_prefix(source_path).unwrap_or(&file);
+            ActionError::InvalidCommand(format!(
+                "output {}/{} is not a regular file, directory or symlink",
+                path,
+                inside.display()
+            ))
+        }
+        e => e,
+    })?;
+    let tree_digest = cas
+        .add_new_blob("remote-execution", &tree.encode_to_vec())
         .await?;
-    info!("{:#?}", output_files);
-    Ok(format_result(
-        output_files,
-        action_digest,
-        stdout_digest,
-        stderr_digest,
-        resp,
-    ))
+    Ok(api::OutputDirectory {
+        path,
+        tree_digest: Some(tree_digest),
+        is_topologically_sorted: false,
+    })
 }
 
-fn format_result(
-    output_files: Vec<api::OutputFile>,
-    action_digest: api::Digest,
-    stdout_digest: api::Digest,
-    stderr_digest: api::Digest,
-    resp: SandboxedActionResp,
-) -> ActionResult {
-    // TODO
-    let result = api::ActionResult {
-        output_files,
-        output_file_symlinks: vec![],
-        output_symlinks: vec![],
-        output_directories: vec![],
-        output_directory_symlinks: vec![],
-        exit_code: resp.status_code,
-        execution_metadata: None,
-        stdout_digest: Some(stdout_digest),
-        stderr_digest: Some(stderr_digest),
-        stdout_raw: vec![],
-        stderr_raw: vec![],
+fn format_result(result: api::ActionResult, timed_out: bool) -> api::ExecuteResponse {
+    // A timed out action still reports its partial logs
+    let status = if timed_out {
+        api::Status {
+            code: tonic::Code::DeadlineExceeded as i32,
+            message: "Action exceeded its timeout and was killed".to_string(),
+            details: vec![],
+        }
+    } else {
+        api::Status {
+            code: 0,
+            message: "".to_string(),
+            details: vec![],
+        }
     };
     let response = api::ExecuteResponse {
         result: Some(result),
         cached_result: false,
-        status: Some(api::Status {
-            code: 0,
-            message: "".to_string(),
-            details: vec![],
-        }),
+        status: Some(status),
         server_logs: HashMap::new(),
         message: String::from(""),
     };
 
-    let metadata: api::ExecuteOperationMetadata = api::ExecuteOperationMetadata {
-        stage: api::execution_stage::Value::Completed.into(),
-        action_digest: Some(action_digest),
-        stdout_stream_name: String::from(""),
-        stderr_stream_name: String::from(""),
-    };
-
-    info!("metadata: {:#?}", metadata);
     info!("response: {:#?}", response);
+    response
+}
 
-    (
-        Some(prost_types::Any {
-            type_url: String::from(
-                "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata",
-            ),
-            value: metadata.encode_to_vec(),
-        }),
-        Some(api::operation::Result::Response(prost_types::Any {
-            type_url: String::from(
-                "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteResponse",
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Execution;
+    use crate::content_storage::digest_of;
+    use crate::execution_queue::ExecutionQueue;
+    use crate::operation_registry::OperationRegistry;
+    use crate::platform::PropertyConfig;
+
+    fn property(name: &str, value: &str) -> api::platform::Property {
+        api::platform::Property {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_unsupported_platforms_before_queueing() {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = ContentStorage::new(dir.path().to_path_buf()).unwrap();
+        let operations = OperationRegistry::new(Duration::from_secs(60), Duration::from_secs(60));
+        let service = ExecutionService::new(
+            cas.clone(),
+            LogStreams::new(dir.path().join("logstreams"), Duration::from_secs(60)).unwrap(),
+            ExecutionRunner::new(
+                operations.clone(),
+                ExecutionQueue::new(1, 1, 0..=0, Duration::from_secs(1)),
             ),
-            value: response.encode_to_vec(),
-        })),
-    )
+            ActionTimeouts {
+                default: Duration::from_secs(60),
+                max: Duration::from_secs(60),
+            },
+            Executor::Remote(Scheduler::new(
+                Duration::from_secs(60),
+                Duration::from_secs(1),
+            )),
+            Verifier::new(0.0, dir.path().join("nondeterminism.log")),
+            PlatformConfig::new(BTreeMap::from([(
+                String::from(platform::NETWORK),
+                PropertyConfig {
+                    values: vec![String::from("off")],
+                    default: None,
+                },
+            )])),
+        );
+
+        let command = api::Command {
+            arguments: vec![String::from("true")],
+            platform: Some(api::Platform {
+                properties: vec![property("arch", "arm64")],
+            }),
+            ..Default::default()
+        };
+        let command_digest = cas
+            .add_new_blob("remote-execution", &command.encode_to_vec())
+            .await
+            .unwrap();
+        for platform in [
+            None,
+            Some(api::Platform {
+                properties: vec![property(platform::NETWORK, "on")],
+            }),
+        ] {
+            let action = api::Action {
+                command_digest: Some(command_digest.clone()),
+                input_root_digest: Some(digest_of(&[])),
+                platform,
+                ..Default::default()
+            };
+            let action_digest = cas
+                .add_new_blob("remote-execution", &action.encode_to_vec())
+                .await
+                .unwrap();
+            let status = service
+                .execute(Request::new(api::ExecuteRequest {
+                    instance_name: String::from("remote-execution"),
+                    action_digest: Some(action_digest),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+        let (queued, _) = operations.list("", 0, "").unwrap();
+        assert!(queued.is_empty());
+    }
 }
@@ -0,0 +1,202 @@
+use crate::{
+    api,
+    asset::{self, AssetError, AssetMirror, Checksum, CHECKSUM_SRI},
+    asset_index::{Asset, AssetIndex, AssetKind},
+    content_storage::ContentStorage,
+};
+use tonic::{Request, Response, Status};
+use tracing::{info, instrument};
+
+pub struct FetchService {
+    cas: ContentStorage,
+    mirror: AssetMirror,
+    index: AssetIndex,
+}
+
+impl FetchService {
+    pub fn new(cas: ContentStorage, mirror: AssetMirror, index: AssetIndex) -> Self {
+        FetchService { cas, mirror, index }
+    }
+
+    /// The newest pushed asset any of the URIs is known as, still fully in the CAS.
+    async fn pushed(
+        &self,
+        kind: AssetKind,
+        instance: &str,
+        uris: &[String],
+        qualifiers: &[api::Qualifier],
+        oldest_content_accepted: Option<prost_types::Timestamp>,
+        checksum: Option<&Checksum>,
+    ) -> Result<Option<(String, Asset)>, Status> {
+        let oldest = asset::system_time(oldest_content_accepted, "oldest_content_accepted")?;
+        for uri in uris {
+            for asset in self
+                .index
+                .lookup(kind, instance, uri, qualifiers, oldest)
+                .await?
+            {
+                let digest = asset.digest();
+                if !self
+                    .cas
+                    .has_blob(instance, &digest)
+                    .await
+                    .map_err(AssetError::from)?
+                {
+                    info!("Pushed content of {} is gone from the CAS", uri);
+                    continue;
+                }
+                // Pushed without a checksum, only blobs can be checked against one
+                if let Some(checksum) = checksum {
+                    if !asset.qualifiers.contains_key(CHECKSUM_SRI) {
+                        if kind == AssetKind::Directory {
+                            continue;
+                        }
+                        let data = self
+                            .cas
+                            .read_to_end(instance, &digest.hash)
+                            .await
+                            .map_err(AssetError::from)?;
+                        if !checksum.matches(&data) {
+                            continue;
+                        }
+                    }
+                }
+                info!("Serving {} as pushed", uri);
+                return Ok(Some((uri.clone(), asset)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Outcome of trying every URI of a request in turn.
+struct Fetched {
+    uri: String,
+    status: api::Status,
+    digest: Option<api::Digest>,
+}
+
+/// The first URI that can be served wins, otherwise the last failure is reported.
+async fn fetch_any<F, Fut>(uris: &[String], fetch: F) -> Result<Fetched, Status>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Result<api::Digest, AssetError>>,
+{
+    let mut failure = None;
+    for uri in uris {
+        match fetch(uri.clone()).await {
+            Ok(digest) => {
+                return Ok(Fetched {
+                    uri: uri.clone(),
+                    status: api::Status::default(),
+                    digest: Some(digest),
+                })
+            }
+            Err(e) if e.is_fetch_error() => {
+                info!("Could not fetch {}: {}", uri, e);
+                failure = Some(Fetched {
+                    uri: uri.clone(),
+                    status: e.to_status(),
+                    digest: None,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    failure.ok_or_else(|| Status::invalid_argument("at least one URI is required"))
+}
+
+#[tonic::async_trait]
+impl api::Fetch for FetchService {
+    #[instrument(skip_all, fields(uris = ?request.get_ref().uris))]
+    async fn fetch_blob(
+        &self,
+        request: Request<api::FetchBlobRequest>,
+    ) -> Result<Response<api::FetchBlobResponse>, Status> {
+        let request = request.into_inner();
+        let checksum = asset::checksum(&request.qualifiers)?;
+        if let Some((uri, asset)) = self
+            .pushed(
+                AssetKind::Blob,
+                &request.instance_name,
+                &request.uris,
+                &request.qualifiers,
+                request.oldest_content_accepted,
+                checksum.as_ref(),
+            )
+            .await?
+        {
+            return Ok(Response::new(api::FetchBlobResponse {
+                status: Some(api::Status::default()),
+                uri,
+                qualifiers: asset.qualifiers(),
+                expires_at: asset.expires_at.map(Into::into),
+                blob_digest: Some(asset.digest()),
+            }));
+        }
+        asset::check_supported(&request.qualifiers)?;
+        let fetched = fetch_any(&request.uris, |uri| {
+            let checksum = checksum.clone();
+            let instance = request.instance_name.clone();
+            async move {
+                self.mirror
+                    .fetch_blob(&self.cas, &instance, &uri, checksum.as_ref())
+                    .await
+            }
+        })
+        .await?;
+        Ok(Response::new(api::FetchBlobResponse {
+            status: Some(fetched.status),
+            uri: fetched.uri,
+            qualifiers: request.qualifiers,
+            expires_at: None,
+            blob_digest: fetched.digest,
+        }))
+    }
+
+    #[instrument(skip_all, fields(uris = ?request.get_ref().uris))]
+    async fn fetch_directory(
+        &self,
+        request: Request<api::FetchDirectoryRequest>,
+    ) -> Result<Response<api::FetchDirectoryResponse>, Status> {
+        let request = request.into_inner();
+        let checksum = asset::checksum(&request.qualifiers)?;
+        if let Some((uri, asset)) = self
+            .pushed(
+                AssetKind::Directory,
+                &request.instance_name,
+                &request.uris,
+                &request.qualifiers,
+                request.oldest_content_accepted,
+                checksum.as_ref(),
+            )
+            .await?
+        {
+            return Ok(Response::new(api::FetchDirectoryResponse {
+                status: Some(api::Status::default()),
+                uri,
+                qualifiers: asset.qualifiers(),
+                expires_at: asset.expires_at.map(Into::into),
+                root_directory_digest: Some(asset.digest()),
+            }));
+        }
+        asset::check_supported(&request.qualifiers)?;
+        let fetched = fetch_any(&request.uris, |uri| {
+            let checksum = checksum.clone();
+            let instance = request.instance_name.clone();
+            async move {
+                self.mirror
+                    .fetch_directory(&self.cas, &instance, &uri, checksum.as_ref())
+                    .await
+            }
+        })
+        .await?;
+        Ok(Response::new(api::FetchDirectoryResponse {
+            status: Some(fetched.status),
+            uri: fetched.uri,
+            qualifiers: request.qualifiers,
+            expires_at: None,
+            root_directory_digest: fetched.digest,
+        }))
+    }
+}
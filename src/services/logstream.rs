@@ -0,0 +1,25 @@
+use crate::{api, log_stream::LogStreams};
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub struct LogStreamService {
+    log_streams: LogStreams,
+}
+
+impl LogStreamService {
+    pub fn new(log_streams: LogStreams) -> Self {
+        LogStreamService { log_streams }
+    }
+}
+
+#[tonic::async_trait]
+impl api::LogStreamService for LogStreamService {
+    #[instrument(skip_all, fields(parent = request.get_ref().parent))]
+    async fn create_log_stream(
+        &self,
+        request: Request<api::CreateLogStreamRequest>,
+    ) -> Result<Response<api::LogStream>, Status> {
+        let stream = self.log_streams.create(&request.get_ref().parent)?;
+        Ok(Response::new(stream))
+    }
+}
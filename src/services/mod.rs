@@ -1,7 +1,9 @@
 //! Handle gRPC API
 
 mod execution;
-pub use execution::ExecutionService;
+pub use execution::{
+    execute_locally, ActionRequest, ActionTimeouts, ExecutionService, Executor, SandboxConfig,
+};
 
 mod operations;
 pub use operations::OperationsService;
@@ -9,11 +11,26 @@ pub use operations::OperationsService;
 mod action_cache;
 pub use action_cache::ActionCacheService;
 
+mod bots;
+pub use bots::BotsService;
+
+mod worker_status;
+pub use worker_status::WorkerStatusService;
+
 mod capabilities;
 pub use capabilities::CapabilitiesService;
 
 mod bytestream;
 pub use bytestream::BytestreamService;
 
+mod fetch;
+pub use fetch::FetchService;
+
+mod push;
+pub use push::PushService;
+
+mod logstream;
+pub use logstream::LogStreamService;
+
 mod content_storage;
 pub use content_storage::ContentStorageService;
@@ -1,50 +1,105 @@
-use crate::{api, content_storage::ContentStorage, execution_runner::ExecutionRunner};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use crate::{api, operation_registry::OperationRegistry};
 use tonic::{Request, Response, Status};
 use tracing::{info, instrument};
 
-pub struct OperationsService {}
+pub struct OperationsService {
+    operations: OperationRegistry,
+}
+
 impl OperationsService {
-    pub fn new() -> Self {
-        OperationsService {}
+    pub fn new(operations: OperationRegistry) -> Self {
+        OperationsService { operations }
     }
 }
 
 #[tonic::async_trait]
 impl api::Operations for OperationsService {
+    #[instrument(skip_all, fields(filter = request.get_ref().filter))]
     async fn list_operations(
         &self,
         request: Request<api::ListOperationsRequest>,
     ) -> Result<Response<api::ListOperationsResponse>, Status> {
-        todo!()
+        let request = request.into_inner();
+        let (operations, next_page_token) = self
+            .operations
+            .list(&request.filter, request.page_size, &request.page_token)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        info!("Listing {} operations", operations.len());
+        Ok(Response::new(api::ListOperationsResponse {
+            operations: operations.iter().map(|op| op.to_operation()).collect(),
+            next_page_token,
+        }))
     }
 
+    #[instrument(skip_all, fields(name = request.get_ref().name))]
     async fn get_operation(
         &self,
         request: Request<api::GetOperationRequest>,
     ) -> Result<Response<api::Operation>, Status> {
-        todo!()
+        let name = &request.get_ref().name;
+        let state = self
+            .operations
+            .get(name)
+            .ok_or_else(|| Status::not_found(format!("unknown operation: {}", name)))?;
+        Ok(Response::new(state.to_operation()))
     }
 
+    #[instrument(skip_all, fields(name = request.get_ref().name))]
     async fn delete_operation(
         &self,
         request: Request<api::DeleteOperationRequest>,
     ) -> Result<Response<()>, Status> {
-        todo!()
+        let name = &request.get_ref().name;
+        if !self.operations.delete(name) {
+            return Err(Status::not_found(format!("unknown operation: {}", name)));
+        }
+        info!("Deleted.");
+        Ok(Response::new(()))
     }
 
+    #[instrument(skip_all, fields(name = request.get_ref().name))]
     async fn cancel_operation(
         &self,
         request: Request<api::CancelOperationRequest>,
     ) -> Result<Response<()>, Status> {
-        todo!()
+        let name = &request.get_ref().name;
+        if !self.operations.cancel(name) {
+            return Err(Status::not_found(format!("unknown operation: {}", name)));
+        }
+        Ok(Response::new(()))
     }
 
+    #[instrument(skip_all, fields(name = request.get_ref().name))]
     async fn wait_operation(
         &self,
         request: Request<api::WaitOperationRequest>,
     ) -> Result<Response<api::Operation>, Status> {
-        todo!()
+        let request = request.into_inner();
+        let mut updates = self
+            .operations
+            .subscribe(&request.name)
+            .ok_or_else(|| Status::not_found(format!("unknown operation: {}", request.name)))?;
+        let timeout = request
+            .timeout
+            .map(std::time::Duration::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("negative timeout"))?;
+
+        let wait_done = async {
+            while !updates.borrow_and_update().done() {
+                if updates.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, wait_done).await;
+            }
+            None => wait_done.await,
+        }
+
+        let state = updates.borrow().clone();
+        Ok(Response::new(state.to_operation()))
     }
 }
@@ -0,0 +1,110 @@
+use crate::{
+    api,
+    asset::{self, AssetError},
+    asset_index::{AssetIndex, AssetKind},
+    content_storage::ContentStorage,
+};
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+
+pub struct PushService {
+    cas: ContentStorage,
+    index: AssetIndex,
+}
+
+impl PushService {
+    pub fn new(cas: ContentStorage, index: AssetIndex) -> Self {
+        PushService { cas, index }
+    }
+
+    /// Pushed content has to be uploaded first, along with everything it references.
+    async fn check_content(
+        &self,
+        instance: &str,
+        digests: Vec<&api::Digest>,
+    ) -> Result<(), AssetError> {
+        for digest in digests {
+            if !self.cas.has_blob(instance, digest).await? {
+                return Err(AssetError::MissingContent(digest.hash.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn push(
+        &self,
+        kind: AssetKind,
+        instance: &str,
+        uris: &[String],
+        qualifiers: &[api::Qualifier],
+        expire_at: Option<prost_types::Timestamp>,
+        digest: Option<&api::Digest>,
+        references_blobs: &[api::Digest],
+        references_directories: &[api::Digest],
+    ) -> Result<(), Status> {
+        if uris.is_empty() {
+            return Err(Status::invalid_argument("at least one URI is required"));
+        }
+        for uri in uris {
+            url::Url::parse(uri).map_err(|e| AssetError::InvalidUri(uri.clone(), e.to_string()))?;
+        }
+        let digest = digest.ok_or_else(|| Status::invalid_argument("a digest is required"))?;
+        let expires_at = asset::system_time(expire_at, "expire_at")?;
+        self.check_content(
+            instance,
+            std::iter::once(digest)
+                .chain(references_blobs)
+                .chain(references_directories)
+                .collect(),
+        )
+        .await?;
+        self.index
+            .push(kind, instance, uris, qualifiers, digest, expires_at)
+            .await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl api::Push for PushService {
+    #[instrument(skip_all, fields(uris = ?request.get_ref().uris))]
+    async fn push_blob(
+        &self,
+        request: Request<api::PushBlobRequest>,
+    ) -> Result<Response<api::PushBlobResponse>, Status> {
+        let request = request.into_inner();
+        self.push(
+            AssetKind::Blob,
+            &request.instance_name,
+            &request.uris,
+            &request.qualifiers,
+            request.expire_at,
+            request.blob_digest.as_ref(),
+            &request.references_blobs,
+            &request.references_directories,
+        )
+        .await?;
+        Ok(Response::new(api::PushBlobResponse {}))
+    }
+
+    #[instrument(skip_all, fields(uris = ?request.get_ref().uris))]
+    async fn push_directory(
+        &self,
+        request: Request<api::PushDirectoryRequest>,
+    ) -> Result<Response<api::PushDirectoryResponse>, Status> {
+        let request = request.into_inner();
+        self.push(
+            AssetKind::Directory,
+            &request.instance_name,
+            &request.uris,
+            &request.qualifiers,
+            request.expire_at,
+            request.root_directory_digest.as_ref(),
+            &request.references_blobs,
+            &request.references_directories,
+        )
+        .await?;
+        Ok(Response::new(api::PushDirectoryResponse {}))
+    }
+}
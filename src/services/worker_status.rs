@@ -0,0 +1,30 @@
+use crate::{api, scheduler::Scheduler};
+use tonic::{Request, Response, Status};
+use tracing::{info, instrument};
+
+pub struct WorkerStatusService {
+    scheduler: Scheduler,
+}
+
+impl WorkerStatusService {
+    pub fn new(scheduler: Scheduler) -> Self {
+        WorkerStatusService { scheduler }
+    }
+}
+
+#[tonic::async_trait]
+impl api::WorkerStatus for WorkerStatusService {
+    #[instrument(skip_all)]
+    async fn list_workers(
+        &self,
+        _request: Request<api::worker::ListWorkersRequest>,
+    ) -> Result<Response<api::worker::ListWorkersResponse>, Status> {
+        let workers = self.scheduler.workers();
+        info!(
+            "Listing {} workers, {} actions waiting",
+            workers.workers.len(),
+            workers.queued_actions
+        );
+        Ok(Response::new(workers))
+    }
+}
@@ -0,0 +1,505 @@
+//! Nondeterminism detection: an action picked for verification runs a second time in a sandbox
+//! of its own and the outputs of both runs are compared.
+//!
+//! Clients only ever see the first run, its result is the one returned and the only one that
+//! could be cached. It is only returned once both runs were compared, so a verified action takes
+//! about twice as long and holds its execution slot throughout. Differences are summed up in its
+//! message, detailed in a server log and appended to a dedicated log on the server, one JSON
+//! line per nondeterministic action.
+
+use crate::api;
+use crate::content_storage::{digest_of, CasError, ContentStorage};
+use crate::execution_runner::ActionError;
+use crate::platform::{self, Platform};
+use prost::Message;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+use uuid::Uuid;
+
+/// Server log holding the differences found, see `ExecuteResponse.server_logs`.
+pub const SERVER_LOG: &str = "nondeterminism";
+
+/// An output whose two runs did not agree. Either side is absent when only one run produced it.
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub output: String,
+    pub first: Option<String>,
+    pub second: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("absent"));
+        write!(
+            f,
+            "{}: {} vs {}",
+            self.output,
+            side(&self.first),
+            side(&self.second)
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    time: String,
+    action_digest: String,
+    differences: &'a [Difference],
+}
+
+fn describe(digest: &Option<api::Digest>) -> Option<String> {
+    digest
+        .as_ref()
+        .map(|digest| format!("{}/{}", digest.hash, digest.size_bytes))
+}
+
+/// What a single output of a run looks like, keyed by path.
+type Outputs = BTreeMap<String, String>;
+
+/// Compare two sets of outputs, an entry present on one side only differs too.
+fn compare(prefix: &str, first: &Outputs, second: &Outputs, differences: &mut Vec<Difference>) {
+    let paths: BTreeSet<&String> = first.keys().chain(second.keys()).collect();
+    for path in paths {
+        let (first, second) = (first.get(path), second.get(path));
+        if first != second {
+            differences.push(Difference {
+                output: format!("{}{}", prefix, path),
+                first: first.cloned(),
+                second: second.cloned(),
+            });
+        }
+    }
+}
+
+/// Every entry of a directory output, by path below it.
+async fn tree_outputs(
+    cas: &ContentStorage,
+    tree_digest: &api::Digest,
+) -> Result<Outputs, CasError> {
+    let tree: api::Tree = cas.get_proto("remote-execution", tree_digest).await?;
+    let children: BTreeMap<String, api::Directory> = tree
+        .children
+        .into_iter()
+        .map(|dir| {
+            let digest = digest_of(&dir.encode_to_vec());
+            (digest.hash, dir)
+        })
+        .collect();
+    let mut outputs = Outputs::new();
+    let mut todo = vec![(String::new(), tree.root.unwrap_or_default())];
+    while let Some((prefix, dir)) = todo.pop() {
+        for file in dir.files {
+            let executable = if file.is_executable {
+                " (executable)"
+            } else {
+                ""
+            };
+            outputs.insert(
+                format!("{}{}", prefix, file.name),
+                format!(
+                    "{}{}",
+                    describe(&file.digest).unwrap_or_default(),
+                    executable
+                ),
+            );
+        }
+        for symlink in dir.symlinks {
+            outputs.insert(
+                format!("{}{}", prefix, symlink.name),
+                format!("-> {}", symlink.target),
+            );
+        }
+        for node in dir.directories {
+            let child = node
+                .digest
+                .as_ref()
+                .and_then(|digest| children.get(&digest.hash))
+                .cloned()
+                .unwrap_or_default();
+            // Kept as an entry of its own so empty directories are compared as well
+            outputs.insert(
+                format!("{}{}/", prefix, node.name),
+                String::from("directory"),
+            );
+            todo.push((format!("{}{}/", prefix, node.name), child));
+        }
+    }
+    Ok(outputs)
+}
+
+/// Every way the results of two runs of the same action differ.
+async fn differences(
+    cas: &ContentStorage,
+    first: &api::ActionResult,
+    second: &api::ActionResult,
+) -> Result<Vec<Difference>, CasError> {
+    let mut differences = vec![];
+    if first.exit_code != second.exit_code {
+        differences.push(Difference {
+            output: String::from("exit code"),
+            first: Some(first.exit_code.to_string()),
+            second: Some(second.exit_code.to_string()),
+        });
+    }
+    for (output, first, second) in [
+        ("stdout", &first.stdout_digest, &second.stdout_digest),
+        ("stderr", &first.stderr_digest, &second.stderr_digest),
+    ] {
+        if first != second {
+            differences.push(Difference {
+                output: String::from(output),
+                first: describe(first),
+                second: describe(second),
+            });
+        }
+    }
+
+    let files = |result: &api::ActionResult| -> Outputs {
+        let files = result.output_files.iter().map(|file| {
+            let executable = if file.is_executable {
+                " (executable)"
+            } else {
+                ""
+            };
+            let digest = describe(&file.digest).unwrap_or_default();
+            (file.path.clone(), format!("{}{}", digest, executable))
+        });
+        let symlinks = result
+            .output_file_symlinks
+            .iter()
+            .chain(&result.output_directory_symlinks)
+            .chain(&result.output_symlinks)
+            .map(|symlink| (symlink.path.clone(), format!("-> {}", symlink.target)));
+        files.chain(symlinks).collect()
+    };
+    compare("", &files(first), &files(second), &mut differences);
+
+    let directories = |result: &api::ActionResult| -> BTreeMap<String, Option<api::Digest>> {
+        result
+            .output_directories
+            .iter()
+            .map(|dir| (dir.path.clone(), dir.tree_digest.clone()))
+            .collect()
+    };
+    let (first_dirs, second_dirs) = (directories(first), directories(second));
+    let paths: BTreeSet<&String> = first_dirs.keys().chain(second_dirs.keys()).collect();
+    for path in paths {
+        match (first_dirs.get(path), second_dirs.get(path)) {
+            (Some(first), Some(second)) if first == second => {}
+            // Narrowed down to the files that differ
+            (Some(Some(first)), Some(Some(second))) => compare(
+                &format!("{}/", path),
+                &tree_outputs(cas, first).await?,
+                &tree_outputs(cas, second).await?,
+                &mut differences,
+            ),
+            (first, second) => differences.push(Difference {
+                output: path.clone(),
+                first: first.map(describe).unwrap_or_default(),
+                second: second.map(describe).unwrap_or_default(),
+            }),
+        }
+    }
+    Ok(differences)
+}
+
+/// Picks the actions to verify and reports the ones that turn out nondeterministic.
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    /// Share of all actions verified, whether or not they ask for it.
+    sample_rate: f64,
+    log: PathBuf,
+}
+
+impl Verifier {
+    pub fn new(sample_rate: f64, log: PathBuf) -> Self {
+        Verifier { sample_rate, log }
+    }
+
+    /// Whether an action running on `platform`, with defaults applied, should run twice.
+    pub fn wanted(&self, platform: &Platform) -> bool {
+        if platform.get(platform::VERIFY) == Some("on") {
+            return true;
+        }
+        // 53 random bits, as many as an f64 holds exactly
+        let random = Uuid::new_v4().as_u64_pair().1 & ((1 << 53) - 1);
+        (random as f64 / (1u64 << 53) as f64) < self.sample_rate
+    }
+
+    /// Compare the second run of an action with the first, reporting any difference on the
+    /// first's response.
+    pub async fn verify(
+        &self,
+        cas: &ContentStorage,
+        action_digest: &api::Digest,
+        first: &mut api::ExecuteResponse,
+        second: Result<api::ExecuteResponse, ActionError>,
+    ) {
+        let Some(first_result) = &first.result else {
+            return;
+        };
+        let second_result = match second {
+            Ok(api::ExecuteResponse {
+                result: Some(result),
+                ..
+            }) => result,
+            Ok(response) => {
+                info!(
+                    "Could not verify {}: {}",
+                    action_digest.hash,
+                    response
+                        .status
+                        .map(|status| status.message)
+                        .unwrap_or_default()
+                );
+                return;
+            }
+            Err(e) => {
+                info!("Could not verify {}: {}", action_digest.hash, e);
+                return;
+            }
+        };
+        let differences = match differences(cas, first_result, &second_result).await {
+            Ok(differences) => differences,
+            Err(e) => {
+                info!(
+                    "Could not compare the runs of {}: {}",
+                    action_digest.hash, e
+                );
+                return;
+            }
+        };
+        if differences.is_empty() {
+            info!("Action {} is deterministic", action_digest.hash);
+            return;
+        }
+        info!(
+            "Action {} is nondeterministic, {} outputs differ",
+            action_digest.hash,
+            differences.len()
+        );
+        if let Err(e) = self.report(cas, action_digest, first, &differences).await {
+            info!("Could not report nondeterminism: {}", e);
+        }
+    }
+
+    async fn report(
+        &self,
+        cas: &ContentStorage,
+        action_digest: &api::Digest,
+        response: &mut api::ExecuteResponse,
+        differences: &[Difference],
+    ) -> Result<(), CasError> {
+        let summary = format!(
+            "Nondeterministic: {} outputs differed when the action ran twice: {}",
+            differences.len(),
+            differences
+                .iter()
+                .map(|difference| difference.output.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        response.message = match response.message.is_empty() {
+            true => summary,
+            false => format!("{}\n{}", response.message, summary),
+        };
+
+        let details: String = differences
+            .iter()
+            .map(|difference| format!("{}\n", difference))
+            .collect();
+        let digest = cas
+            .add_new_blob("remote-execution", details.as_bytes())
+            .await?;
+        response.server_logs.insert(
+            String::from(SERVER_LOG),
+            api::LogFile {
+                digest: Some(digest),
+                human_readable: true,
+            },
+        );
+
+        let entry = LogEntry {
+            time: prost_types::Timestamp::from(SystemTime::now()).to_string(),
+            action_digest: format!("{}/{}", action_digest.hash, action_digest.size_bytes),
+            differences,
+        };
+        let mut line = serde_json::to_vec(&entry).map_err(std::io::Error::from)?;
+        line.push(b'\n');
+        // Appended in one write so concurrent reports do not interleave
+        let mut log = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log)
+            .await?;
+        log.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(data: &str) -> Option<api::Digest> {
+        Some(digest_of(data.as_bytes()))
+    }
+
+    fn file(path: &str, data: &str) -> api::OutputFile {
+        api::OutputFile {
+            path: path.to_string(),
+            digest: digest(data),
+            ..Default::default()
+        }
+    }
+
+    fn outputs(differences: &[Difference]) -> Vec<&str> {
+        differences
+            .iter()
+            .map(|difference| difference.output.as_str())
+            .collect()
+    }
+
+    /// A directory output holding files with the given contents, stored in `cas`.
+    async fn directory(
+        cas: &ContentStorage,
+        path: &str,
+        files: &[(&str, &str)],
+    ) -> api::OutputDirectory {
+        let root = api::Directory {
+            files: files
+                .iter()
+                .map(|(name, data)| api::FileNode {
+                    name: name.to_string(),
+                    digest: digest(data),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let tree = api::Tree {
+            root: Some(root),
+            children: vec![],
+        };
+        let tree_digest = cas
+            .add_new_blob("remote-execution", &tree.encode_to_vec())
+            .await
+            .unwrap();
+        api::OutputDirectory {
+            path: path.to_string(),
+            tree_digest: Some(tree_digest),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = ContentStorage::new(dir.path().to_path_buf()).unwrap();
+        let result = api::ActionResult {
+            output_files: vec![file("out", "a")],
+            stdout_digest: digest("hello"),
+            ..Default::default()
+        };
+        assert!(differences(&cas, &result, &result)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn differing_files_and_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = ContentStorage::new(dir.path().to_path_buf()).unwrap();
+        let first = api::ActionResult {
+            exit_code: 0,
+            output_files: vec![file("same", "a"), file("changed", "b"), file("first", "c")],
+            stdout_digest: digest("one"),
+            ..Default::default()
+        };
+        let second = api::ActionResult {
+            exit_code: 1,
+            output_files: vec![file("same", "a"), file("changed", "d")],
+            stdout_digest: digest("two"),
+            ..Default::default()
+        };
+        let differences = differences(&cas, &first, &second).await.unwrap();
+        assert_eq!(
+            outputs(&differences),
+            ["exit code", "stdout", "changed", "first"]
+        );
+        assert_eq!(differences[3].second, None);
+        assert_eq!(
+            differences[3].to_string(),
+            format!("first: {} vs absent", describe(&digest("c")).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn directories_are_compared_by_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = ContentStorage::new(dir.path().to_path_buf()).unwrap();
+        let first = api::ActionResult {
+            output_directories: vec![
+                directory(&cas, "out", &[("same", "a"), ("changed", "b")]).await,
+                directory(&cas, "gone", &[]).await,
+            ],
+            ..Default::default()
+        };
+        let second = api::ActionResult {
+            output_directories: vec![
+                directory(
+                    &cas,
+                    "out",
+                    &[("same", "a"), ("changed", "c"), ("new", "d")],
+                )
+                .await,
+            ],
+            ..Default::default()
+        };
+        let differences = differences(&cas, &first, &second).await.unwrap();
+        assert_eq!(outputs(&differences), ["gone", "out/changed", "out/new"]);
+    }
+
+    #[tokio::test]
+    async fn report_on_the_response_and_in_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let cas = ContentStorage::new(dir.path().to_path_buf()).unwrap();
+        let log = dir.path().join("nondeterminism.log");
+        let verifier = Verifier::new(0.0, log.clone());
+        let response = |data| api::ExecuteResponse {
+            result: Some(api::ActionResult {
+                output_files: vec![file("out", data)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let action_digest = digest_of(b"action");
+
+        let mut first = response("a");
+        verifier
+            .verify(&cas, &action_digest, &mut first, Ok(response("a")))
+            .await;
+        assert!(first.message.is_empty() && first.server_logs.is_empty());
+        assert!(!log.exists());
+
+        verifier
+            .verify(&cas, &action_digest, &mut first, Ok(response("b")))
+            .await;
+        assert!(first.message.contains("Nondeterministic"));
+        let details = first.server_logs[SERVER_LOG].digest.clone().unwrap();
+        let details = cas
+            .read_to_end("remote-execution", &details.hash)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&details).starts_with("out: "));
+        let logged = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(logged.lines().count(), 1);
+        assert!(logged.contains(&action_digest.hash));
+    }
+}
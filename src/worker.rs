@@ -0,0 +1,549 @@
+//! `gaudi worker`: runs actions leased from a scheduler through the Remote Workers API.
+//!
+//! Inputs are copied from the scheduler's CAS into a local one first, then the action runs in a
+//! sandbox just like on a standalone server. Its logs are forwarded while it runs and its
+//! outputs are uploaded to the scheduler once it is done.
+
+use crate::api::{self, remoteworkers, ByteStreamClient};
+use crate::config::Config;
+use crate::content_storage::{self, ContentStorage, CHUNK_SIZE};
+use crate::execution_queue;
+use crate::execution_runner::ActionError;
+use crate::log_stream::{LogReader, LogStreams};
+use crate::scheduler;
+use crate::services::{execute_locally, ActionRequest, SandboxConfig};
+use crate::util::hostname;
+use futures::future::BoxFuture;
+use prost::Message;
+use remoteworkers::bots_client::BotsClient;
+use remoteworkers::LeaseState;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Channel, Endpoint};
+use tracing::info;
+
+/// Local CAS instance inputs are staged in, the one sandboxes are laid out from.
+const INSTANCE: &str = "remote-execution";
+/// How often a busy worker tells the scheduler it is still on the job.
+const HEARTBEAT: Duration = Duration::from_secs(5);
+/// How often a worker running actions asks for more while it has free slots.
+const POLL: Duration = Duration::from_secs(1);
+/// Pause before trying again to reach a scheduler that did not answer.
+const RETRY: Duration = Duration::from_secs(1);
+/// Local logs are only kept until they are forwarded and uploaded.
+const LOG_RETENTION: Duration = Duration::from_secs(60);
+
+#[derive(clap::Args, Debug)]
+pub struct WorkerArgs {
+    /// Scheduler to lease actions from, e.g. `http://127.0.0.1:50051`.
+    #[arg(long)]
+    scheduler: String,
+
+    /// Local storage directory, inputs stay cached here between actions.
+    #[arg(short, long)]
+    dir: PathBuf,
+
+    /// Worker configuration file, only the platform, mounts, toolchains and images apply.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Name the worker reports to the scheduler, the host name and process id by default.
+    #[arg(long)]
+    bot_id: Option<String>,
+
+    /// Accept input symlinks pointing at absolute paths, resolved inside the sandbox.
+    #[arg(long)]
+    allow_absolute_symlinks: bool,
+
+    /// Directory holding the sandbox of every running action, one subdirectory each.
+    #[arg(long, default_value = "/tmp/gaudi/sandbox")]
+    sandbox_root: PathBuf,
+
+    /// Actions run at once. Defaults to what the host's CPUs and memory allow.
+    #[arg(long)]
+    slots: Option<usize>,
+
+    /// MiB of memory to set aside for each slot when sizing the default slot count.
+    #[arg(long, default_value_t = 2048)]
+    memory_per_slot: u64,
+}
+
+/// Lease actions from the scheduler until the process is killed.
+pub async fn run(args: WorkerArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // Mounts are set up from inside the sandbox, so its paths must be absolute
+    std::fs::create_dir_all(&args.sandbox_root)?;
+    let sandbox_root = std::fs::canonicalize(&args.sandbox_root)?;
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let sandbox = SandboxConfig::new(
+        &config,
+        args.allow_absolute_symlinks,
+        args.dir.join("images"),
+    )?;
+    let log_streams = LogStreams::new(args.dir.join("logstreams"), LOG_RETENTION)?;
+    let cas = ContentStorage::new(args.dir)?;
+    let channel = Endpoint::from_shared(args.scheduler.clone())?.connect_lazy();
+    let bot_id = args
+        .bot_id
+        .unwrap_or_else(|| format!("{}-{}", hostname(), std::process::id()));
+    let slots = args
+        .slots
+        .unwrap_or_else(|| execution_queue::host_slots(args.memory_per_slot * 1024 * 1024))
+        .max(1);
+    info!(
+        "Worker {} leasing up to {} actions from {}",
+        bot_id, slots, args.scheduler
+    );
+
+    let worker = Arc::new(Worker {
+        bot_id,
+        slots,
+        channel,
+        cas,
+        log_streams,
+        sandbox_root,
+        sandbox,
+    });
+    loop {
+        worker.serve().await;
+        tokio::time::sleep(RETRY).await;
+    }
+}
+
+struct Worker {
+    bot_id: String,
+    slots: usize,
+    channel: Channel,
+    /// Holds the inputs and outputs of actions before they move to or from the scheduler.
+    cas: ContentStorage,
+    log_streams: LogStreams,
+    sandbox_root: PathBuf,
+    sandbox: SandboxConfig,
+}
+
+/// An action the worker is busy with, killed when dropped.
+struct Running {
+    task: JoinHandle<api::ExecuteResponse>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn completed(lease_id: String, response: &api::ExecuteResponse) -> remoteworkers::Lease {
+    remoteworkers::Lease {
+        id: lease_id,
+        state: LeaseState::Completed as i32,
+        status: Some(api::Status::default()),
+        result: Some(prost_types::Any {
+            type_url: String::from(
+                "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteResponse",
+            ),
+            value: response.encode_to_vec(),
+        }),
+        ..Default::default()
+    }
+}
+
+/// ByteStream resource name of `name` in an instance of the scheduler.
+fn resource_name(instance: &str, name: String) -> String {
+    if instance.is_empty() {
+        name
+    } else {
+        format!("{}/{}", instance, name)
+    }
+}
+
+impl Worker {
+    /// How the worker advertises itself: every accepted value of a platform property is a
+    /// config, an empty value accepting any.
+    fn describe(&self) -> remoteworkers::Worker {
+        let mut configs = vec![];
+        for (name, values) in self.sandbox.platform.properties() {
+            let values = match values {
+                [] => &[String::new()][..],
+                values => values,
+            };
+            configs.extend(values.iter().map(|value| remoteworkers::worker::Config {
+                key: name.clone(),
+                value: value.clone(),
+            }));
+        }
+        remoteworkers::Worker {
+            devices: vec![remoteworkers::Device {
+                handle: hostname(),
+                properties: vec![],
+            }],
+            properties: vec![remoteworkers::worker::Property {
+                key: String::from(scheduler::SLOTS),
+                value: self.slots.to_string(),
+            }],
+            configs,
+        }
+    }
+
+    /// Run leases for one bot session, returning once the scheduler no longer knows it.
+    async fn serve(self: &Arc<Self>) {
+        let mut bots = BotsClient::new(self.channel.clone());
+        let mut session = match bots
+            .create_bot_session(remoteworkers::CreateBotSessionRequest {
+                parent: String::new(),
+                bot_session: Some(remoteworkers::BotSession {
+                    bot_id: self.bot_id.clone(),
+                    status: remoteworkers::BotStatus::Ok as i32,
+                    worker: Some(self.describe()),
+                    version: String::from(env!("CARGO_PKG_VERSION")),
+                    ..Default::default()
+                }),
+            })
+            .await
+        {
+            Ok(session) => session.into_inner(),
+            Err(e) => {
+                info!("Could not reach the scheduler: {}", e.message());
+                return;
+            }
+        };
+        info!("Opened bot session {}", session.name);
+
+        // By lease id
+        let mut running: HashMap<String, Running> = HashMap::new();
+        // Finished leases, reported until the scheduler took them in
+        let mut finished = vec![];
+        loop {
+            session.leases = finished.clone();
+            session
+                .leases
+                .extend(running.keys().map(|id| remoteworkers::Lease {
+                    id: id.clone(),
+                    state: LeaseState::Active as i32,
+                    ..Default::default()
+                }));
+            let update = bots
+                .update_bot_session(remoteworkers::UpdateBotSessionRequest {
+                    name: session.name.clone(),
+                    bot_session: Some(session.clone()),
+                    update_mask: Some(prost_types::FieldMask {
+                        paths: vec![String::from("status"), String::from("leases")],
+                    }),
+                })
+                .await;
+            let update = match update {
+                Ok(update) => update.into_inner(),
+                Err(e) if e.code() == tonic::Code::NotFound => {
+                    info!("The scheduler forgot bot session {}", session.name);
+                    return;
+                }
+                Err(e) => {
+                    info!("Could not reach the scheduler: {}", e.message());
+                    tokio::time::sleep(RETRY).await;
+                    continue;
+                }
+            };
+            finished.clear();
+
+            for lease in update.leases {
+                match LeaseState::from_i32(lease.state) {
+                    Some(LeaseState::Pending) if !running.contains_key(&lease.id) => {
+                        let assignment = lease
+                            .payload
+                            .as_ref()
+                            .and_then(|payload| api::Assignment::decode(&payload.value[..]).ok());
+                        let Some(assignment) = assignment else {
+                            finished.push(remoteworkers::Lease {
+                                id: lease.id,
+                                state: LeaseState::Completed as i32,
+                                status: Some(api::Status {
+                                    code: tonic::Code::InvalidArgument as i32,
+                                    message: String::from("lease carries no assignment"),
+                                    details: vec![],
+                                }),
+                                ..Default::default()
+                            });
+                            continue;
+                        };
+                        info!("Accepted lease {}", lease.id);
+                        let worker = self.clone();
+                        running.insert(
+                            lease.id,
+                            Running {
+                                task: tokio::spawn(async move { worker.execute(assignment).await }),
+                            },
+                        );
+                    }
+                    Some(LeaseState::Cancelled) if running.remove(&lease.id).is_some() => {
+                        info!("Lease {} was cancelled", lease.id);
+                    }
+                    _ => {}
+                }
+            }
+
+            // Report back as soon as an action is done, idle updates wait for work on their own
+            if running.is_empty() {
+                continue;
+            }
+            let wait = match running.len() < self.slots {
+                true => POLL,
+                false => HEARTBEAT,
+            };
+            let done = futures::future::select_all(running.iter_mut().map(|(id, current)| {
+                Box::pin(async move { (id.clone(), (&mut current.task).await) })
+            }));
+            let done = tokio::select! {
+                ((id, result), _, _) = done => Some((id, result)),
+                _ = tokio::time::sleep(wait) => None,
+            };
+            if let Some((id, result)) = done {
+                let response = result.unwrap_or_else(|e| {
+                    ActionError::SandboxIoError(std::io::Error::other(e.to_string())).to_response()
+                });
+                running.remove(&id);
+                finished.push(completed(id, &response));
+            }
+        }
+    }
+
+    async fn execute(self: Arc<Self>, assignment: api::Assignment) -> api::ExecuteResponse {
+        match self.try_execute(assignment).await {
+            Ok(response) => response,
+            Err(e) => {
+                info!("Action failed: {}", e);
+                e.to_response()
+            }
+        }
+    }
+
+    async fn try_execute(
+        &self,
+        assignment: api::Assignment,
+    ) -> Result<api::ExecuteResponse, ActionError> {
+        let instance = &assignment.instance_name;
+        let action = assignment
+            .action
+            .ok_or_else(|| ActionError::InvalidAction(String::from("no action")))?;
+        let timeout = action
+            .timeout
+            .clone()
+            .and_then(|timeout| Duration::try_from(timeout).ok())
+            .ok_or_else(|| ActionError::InvalidAction(String::from("no timeout")))?;
+        let request = ActionRequest::new(&action, timeout)?;
+        // Whatever the scheduler lacks is reported as missing once the action is laid out
+        self.fetch_blob(instance, request.command_digest()).await?;
+        self.fetch_tree(instance, request.root_digest()).await?;
+
+        let stdout = self.log_streams.open("")?;
+        let stderr = self.log_streams.open("")?;
+        let mut forwarders = vec![];
+        for (log, resource_name) in [
+            (&stdout, &assignment.stdout_stream),
+            (&stderr, &assignment.stderr_stream),
+        ] {
+            if let (false, Some(reader)) = (
+                resource_name.is_empty(),
+                self.log_streams.reader(log.name(), 0)?,
+            ) {
+                forwarders.push(tokio::spawn(forward_log(
+                    self.channel.clone(),
+                    reader,
+                    resource_name.clone(),
+                )));
+            }
+        }
+        let metadata = api::ExecutedActionMetadata {
+            worker: self.bot_id.clone(),
+            ..Default::default()
+        };
+        let result = execute_locally(
+            self.cas.clone(),
+            &self.sandbox_root,
+            &self.sandbox,
+            request.logs(stdout.path(), stderr.path()),
+            metadata,
+        )
+        .await;
+        // Forwarders stop once they caught up with the finalized logs
+        drop((stdout, stderr));
+        for forwarder in forwarders {
+            let _ = forwarder.await;
+        }
+        let response = result?;
+        self.upload_outputs(instance, &response).await?;
+        Ok(response)
+    }
+
+    /// Copy a blob from the scheduler unless it is already here. Returns whether it is now
+    /// stored locally.
+    async fn fetch_blob(&self, instance: &str, digest: &api::Digest) -> Result<bool, ActionError> {
+        if self.cas.has_blob(INSTANCE, digest).await? {
+            return Ok(true);
+        }
+        let mut client = ByteStreamClient::new(self.channel.clone());
+        let read = client
+            .read(api::ReadRequest {
+                resource_name: resource_name(
+                    instance,
+                    format!("blobs/{}/{}", digest.hash, digest.size_bytes),
+                ),
+                read_offset: 0,
+                read_limit: 0,
+            })
+            .await;
+        let mut stream = match read {
+            Ok(stream) => stream.into_inner(),
+            Err(e) if e.code() == tonic::Code::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut blob = self.cas.blob_writer(INSTANCE).await?;
+        loop {
+            match stream.message().await {
+                Ok(Some(chunk)) => blob.write(&chunk.data).await?,
+                Ok(None) => break,
+                Err(e) if e.code() == tonic::Code::NotFound => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Content that does not match its digest is stored under its own and stays missing
+        let stored = blob.finish().await?;
+        Ok(stored == *digest)
+    }
+
+    /// Copy a Directory and everything below it from the scheduler.
+    fn fetch_tree<'a>(
+        &'a self,
+        instance: &'a str,
+        digest: &'a api::Digest,
+    ) -> BoxFuture<'a, Result<(), ActionError>> {
+        Box::pin(async move {
+            if !self.fetch_blob(instance, digest).await? {
+                return Ok(());
+            }
+            let dir: api::Directory = self.cas.get_proto(INSTANCE, digest).await?;
+            for digest in dir.files.iter().filter_map(|file| file.digest.as_ref()) {
+                self.fetch_blob(instance, digest).await?;
+            }
+            for digest in dir.directories.iter().filter_map(|dir| dir.digest.as_ref()) {
+                self.fetch_tree(instance, digest).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Store every blob the response refers to in the scheduler's CAS.
+    async fn upload_outputs(
+        &self,
+        instance: &str,
+        response: &api::ExecuteResponse,
+    ) -> Result<(), ActionError> {
+        let Some(result) = &response.result else {
+            return Ok(());
+        };
+        let mut digests: Vec<api::Digest> = result
+            .stdout_digest
+            .iter()
+            .chain(&result.stderr_digest)
+            .chain(
+                result
+                    .output_files
+                    .iter()
+                    .filter_map(|file| file.digest.as_ref()),
+            )
+            .cloned()
+            .collect();
+        for output in &result.output_directories {
+            let Some(tree_digest) = &output.tree_digest else {
+                continue;
+            };
+            digests.push(tree_digest.clone());
+            let tree: api::Tree = self.cas.get_proto(INSTANCE, tree_digest).await?;
+            for dir in tree.root.iter().chain(&tree.children) {
+                digests.extend(dir.files.iter().filter_map(|file| file.digest.clone()));
+                digests.extend(dir.directories.iter().filter_map(|dir| dir.digest.clone()));
+            }
+        }
+        digests.sort_by(|a, b| a.hash.cmp(&b.hash));
+        digests.dedup();
+
+        let mut client = ByteStreamClient::new(self.channel.clone());
+        for digest in digests {
+            let name = format!(
+                "uploads/{}/blobs/{}/{}",
+                uuid::Uuid::new_v4(),
+                digest.hash,
+                digest.size_bytes
+            );
+            let resource_name = resource_name(instance, name);
+            let mut file = self.cas.open_blob(INSTANCE, &digest.hash).await?;
+            let (tx, rx) = mpsc::channel(4);
+            let send = async move {
+                let mut write_offset = 0;
+                loop {
+                    let data = content_storage::read_chunk(&mut file).await?;
+                    let len = data.len() as i64;
+                    let finish_write = data.len() < CHUNK_SIZE;
+                    let request = api::WriteRequest {
+                        resource_name: resource_name.clone(),
+                        write_offset,
+                        finish_write,
+                        data,
+                    };
+                    // The upload already failed if the request cannot be sent, it says why
+                    if tx.send(request).await.is_err() || finish_write {
+                        return Ok::<_, ActionError>(());
+                    }
+                    write_offset += len;
+                }
+            };
+            let (written, sent) = tokio::join!(client.write(ReceiverStream::new(rx)), send);
+            sent?;
+            written?;
+        }
+        Ok(())
+    }
+}
+
+/// Append everything written to a local log to the scheduler's log stream behind
+/// `resource_name`, finishing it once the local log is finalized.
+async fn forward_log(channel: Channel, mut reader: LogReader, resource_name: String) {
+    let (tx, rx) = mpsc::channel(16);
+    let mut client = ByteStreamClient::new(channel);
+    let upload = tokio::spawn(async move { client.write(ReceiverStream::new(rx)).await });
+    let mut offset = 0;
+    loop {
+        let (data, finish_write) = match reader.next().await {
+            Ok(Some(data)) => (data, false),
+            Ok(None) => (vec![], true),
+            Err(e) => {
+                info!("Could not read log: {}", e);
+                (vec![], true)
+            }
+        };
+        let len = data.len() as i64;
+        let request = api::WriteRequest {
+            resource_name: resource_name.clone(),
+            write_offset: offset,
+            finish_write,
+            data,
+        };
+        if tx.send(request).await.is_err() || finish_write {
+            break;
+        }
+        offset += len;
+    }
+    drop(tx);
+    if let Ok(Err(e)) = upload.await {
+        info!(
+            "Could not forward log to {}: {}",
+            resource_name,
+            e.message()
+        );
+    }
+}